@@ -0,0 +1,594 @@
+use crate::cpu::memory::{PROGRAM_CODE_BASE, XO_MEMORY_SIZE};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// An error in the assembly source, with the 1-based line and column it was
+/// found at.
+#[derive(Debug)]
+pub struct AssemblyError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+/// Assembles the source file at `path` into a binary to be loaded at
+/// `PROGRAM_CODE_BASE`.
+///
+/// The source uses the mnemonics of `opcode!`, one instruction per line, with
+/// `;` starting a comment. Besides instructions, a line may hold:
+///
+/// * `name:` to define a label, optionally followed by an instruction.
+/// * `name equ value` to define a constant. The value may only refer to
+///   symbols defined above it.
+/// * `db value, ...` and `dw value, ...` to emit bytes and big-endian words.
+/// * `include "path"` to assemble another file in place, with the path
+///   relative to the including file.
+///
+/// Values are decimal, `0x` hexadecimal or `0b` binary numbers, labels or
+/// constants.
+pub fn assemble_file(path: &Path) -> Result<Vec<u8>, AssemblyError> {
+    let mut assembler = Assembler::new();
+    assembler.read_file(path, None)?;
+    assembler.encode()
+}
+
+/// Assembles `source` as `assemble_file` does. Includes are relative to the
+/// working directory.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssemblyError> {
+    let mut assembler = Assembler::new();
+    assembler.read_source(source, "<input>", Path::new(""))?;
+    assembler.encode()
+}
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Clone)]
+struct Location {
+    file: String,
+    line: usize,
+}
+
+impl Location {
+    fn new(file: &str, line: usize) -> Location {
+        Location {
+            file: file.to_string(),
+            line,
+        }
+    }
+
+    fn error(&self, column: usize, message: String) -> AssemblyError {
+        AssemblyError {
+            file: self.file.clone(),
+            line: self.line,
+            column,
+            message,
+        }
+    }
+}
+
+/// A piece of a source line, with the column it starts at.
+#[derive(Clone)]
+struct Token {
+    text: String,
+    column: usize,
+}
+
+enum Operand {
+    Register(u8),
+    Index,
+    IndirectIndex,
+    DelayTimer,
+    SoundTimer,
+    Key,
+    Flags,
+    Long(Token),
+    Value(Token),
+}
+
+enum Statement {
+    Instruction(Token, Vec<(Operand, usize)>),
+    Bytes(Vec<Token>),
+    Words(Vec<Token>),
+}
+
+struct Assembler {
+    statements: Vec<(Location, Statement)>,
+    symbols: HashMap<String, u16>,
+    address: usize,
+    include_depth: usize,
+}
+
+impl Assembler {
+    fn new() -> Assembler {
+        Assembler {
+            statements: Vec::new(),
+            symbols: HashMap::new(),
+            address: PROGRAM_CODE_BASE,
+            include_depth: 0,
+        }
+    }
+
+    fn read_file(
+        &mut self,
+        path: &Path,
+        included_from: Option<(&Location, usize)>,
+    ) -> Result<(), AssemblyError> {
+        let source = fs::read_to_string(path).map_err(|error| {
+            let message = format!("Can't read {}: {}", path.display(), error);
+            match included_from {
+                Some((location, column)) => location.error(column, message),
+                None => Location::new(&path.display().to_string(), 0).error(0, message),
+            }
+        })?;
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        self.read_source(&source, &path.display().to_string(), directory)
+    }
+
+    /// The first pass: parses every statement and assigns addresses to the
+    /// labels, so that the second pass can resolve forward references.
+    fn read_source(
+        &mut self,
+        source: &str,
+        file: &str,
+        directory: &Path,
+    ) -> Result<(), AssemblyError> {
+        for (index, text) in source.lines().enumerate() {
+            let location = Location::new(file, index + 1);
+            let code = match text.find(';') {
+                Some(comment) => &text[..comment],
+                None => text,
+            };
+            let mut token = match first_word(code, 0) {
+                Some(token) => token,
+                None => continue,
+            };
+
+            if token.text.ends_with(':') {
+                let name = &token.text[..token.text.len() - 1];
+                self.define(&location, token.column, name, self.address as u16)?;
+                let label_end = token.column - 1 + token.text.len();
+                token = match first_word(&code[label_end..], label_end) {
+                    Some(token) => token,
+                    None => continue,
+                };
+            }
+
+            let rest_start = token.column - 1 + token.text.len();
+            let rest = &code[rest_start..];
+            let mnemonic = token.text.to_uppercase();
+
+            if let Some(value) = first_word(rest, rest_start) {
+                if value.text.eq_ignore_ascii_case("equ") {
+                    let value_start = value.column - 1 + value.text.len();
+                    let value = split_operands(&code[value_start..], value_start);
+                    if value.len() != 1 {
+                        return Err(
+                            location.error(token.column, "equ takes a single value".to_string())
+                        );
+                    }
+                    let value = self.value(&location, &value[0], 0xffff)?;
+                    self.define(&location, token.column, &token.text, value)?;
+                    continue;
+                }
+            }
+
+            let statement = match mnemonic.as_str() {
+                "INCLUDE" => {
+                    let path = rest.trim();
+                    let column = rest_start + rest.find(path).unwrap_or(0) + 1;
+                    if path.len() < 2 || !path.starts_with('"') || !path.ends_with('"') {
+                        return Err(
+                            location.error(column, "include needs a quoted path".to_string())
+                        );
+                    }
+                    if self.include_depth == MAX_INCLUDE_DEPTH {
+                        return Err(
+                            location.error(column, "Includes are nested too deeply".to_string())
+                        );
+                    }
+                    let path: PathBuf = directory.join(&path[1..path.len() - 1]);
+                    self.include_depth += 1;
+                    self.read_file(&path, Some((&location, column)))?;
+                    self.include_depth -= 1;
+                    continue;
+                }
+                "DB" => Statement::Bytes(split_operands(rest, rest_start)),
+                "DW" => Statement::Words(split_operands(rest, rest_start)),
+                _ => {
+                    let operands = split_operands(rest, rest_start)
+                        .into_iter()
+                        .map(|operand| {
+                            let column = operand.column;
+                            (parse_operand(operand), column)
+                        })
+                        .collect();
+                    Statement::Instruction(token, operands)
+                }
+            };
+
+            self.address += match &statement {
+                Statement::Bytes(values) => values.len(),
+                Statement::Words(values) => values.len() * 2,
+                Statement::Instruction(_, operands) => match operands.as_slice() {
+                    [(Operand::Index, _), (Operand::Long(_), _)] => 4,
+                    _ => 2,
+                },
+            };
+            if self.address > XO_MEMORY_SIZE {
+                return Err(location.error(1, "Program doesn't fit in memory".to_string()));
+            }
+            self.statements.push((location, statement));
+        }
+
+        Ok(())
+    }
+
+    fn define(
+        &mut self,
+        location: &Location,
+        column: usize,
+        name: &str,
+        value: u16,
+    ) -> Result<(), AssemblyError> {
+        if !is_symbol(name) {
+            return Err(location.error(column, format!("Invalid symbol name {}", name)));
+        }
+        if self.symbols.insert(name.to_string(), value).is_some() {
+            return Err(location.error(column, format!("{} is already defined", name)));
+        }
+
+        Ok(())
+    }
+
+    /// The second pass: encodes every statement now that all the labels are
+    /// known.
+    fn encode(&self) -> Result<Vec<u8>, AssemblyError> {
+        let mut binary = Vec::new();
+        for (location, statement) in &self.statements {
+            match statement {
+                Statement::Bytes(values) => {
+                    for value in values {
+                        binary.push(self.value(location, value, 0xff)? as u8);
+                    }
+                }
+                Statement::Words(values) => {
+                    for value in values {
+                        let word = self.value(location, value, 0xffff)?;
+                        binary.extend_from_slice(&word.to_be_bytes());
+                    }
+                }
+                Statement::Instruction(mnemonic, operands) => {
+                    for word in self.encode_instruction(location, mnemonic, operands)? {
+                        binary.extend_from_slice(&word.to_be_bytes());
+                    }
+                }
+            }
+        }
+
+        Ok(binary)
+    }
+
+    fn encode_instruction(
+        &self,
+        location: &Location,
+        mnemonic: &Token,
+        operands: &[(Operand, usize)],
+    ) -> Result<Vec<u16>, AssemblyError> {
+        use Operand::*;
+
+        let nibble = |token: &Token| self.value(location, token, 0xf);
+        let byte = |token: &Token| self.value(location, token, 0xff);
+        let address = |token: &Token| self.value(location, token, 0xfff);
+        let xy = |x: &u8, y: &u8| u16::from(*x) << 8 | u16::from(*y) << 4;
+        let x = |x: &u8| u16::from(*x) << 8;
+
+        let kinds: Vec<&Operand> = operands.iter().map(|(operand, _)| operand).collect();
+        let opcode = match (mnemonic.text.to_uppercase().as_str(), kinds.as_slice()) {
+            ("CLS", []) => 0x00e0,
+            ("RET", []) => 0x00ee,
+            ("SCD", [Value(n)]) => 0x00c0 | nibble(n)?,
+            ("SCU", [Value(n)]) => 0x00d0 | nibble(n)?,
+            ("SCR", []) => 0x00fb,
+            ("SCL", []) => 0x00fc,
+            ("EXIT", []) => 0x00fd,
+            ("LOW", []) => 0x00fe,
+            ("HIGH", []) => 0x00ff,
+            ("SYS", [Value(addr)]) => address(addr)?,
+            ("JMP", [Value(addr)]) => 0x1000 | address(addr)?,
+            ("JMP", [Register(0), Value(addr)]) => 0xb000 | address(addr)?,
+            ("CALL", [Value(addr)]) => 0x2000 | address(addr)?,
+            ("SKE", [Register(vx), Value(nn)]) => 0x3000 | x(vx) | byte(nn)?,
+            ("SKNE", [Register(vx), Value(nn)]) => 0x4000 | x(vx) | byte(nn)?,
+            ("SKE", [Register(vx), Register(vy)]) => 0x5000 | xy(vx, vy),
+            ("SAVE", [Register(vx), Register(vy)]) => 0x5002 | xy(vx, vy),
+            ("LOAD", [Register(vx), Register(vy)]) => 0x5003 | xy(vx, vy),
+            ("MOV", [Register(vx), Value(nn)]) => 0x6000 | x(vx) | byte(nn)?,
+            ("ADD", [Register(vx), Value(nn)]) => 0x7000 | x(vx) | byte(nn)?,
+            ("MOV", [Register(vx), Register(vy)]) => 0x8000 | xy(vx, vy),
+            ("OR", [Register(vx), Register(vy)]) => 0x8001 | xy(vx, vy),
+            ("AND", [Register(vx), Register(vy)]) => 0x8002 | xy(vx, vy),
+            ("XOR", [Register(vx), Register(vy)]) => 0x8003 | xy(vx, vy),
+            ("ADD", [Register(vx), Register(vy)]) => 0x8004 | xy(vx, vy),
+            ("SUB", [Register(vx), Register(vy)]) => 0x8005 | xy(vx, vy),
+            ("SHR", [Register(vx)]) => 0x8006 | x(vx),
+            ("SHR", [Register(vx), Register(vy)]) => 0x8006 | xy(vx, vy),
+            ("RSUB", [Register(vx), Register(vy)]) => 0x8007 | xy(vx, vy),
+            ("SHL", [Register(vx)]) => 0x800e | x(vx),
+            ("SHL", [Register(vx), Register(vy)]) => 0x800e | xy(vx, vy),
+            ("SKNE", [Register(vx), Register(vy)]) => 0x9000 | xy(vx, vy),
+            ("MOV", [Index, Value(addr)]) => 0xa000 | address(addr)?,
+            ("MOV", [Index, Long(addr)]) => {
+                return Ok(vec![0xf000, self.value(location, addr, 0xffff)?]);
+            }
+            ("RND", [Register(vx), Value(nn)]) => 0xc000 | x(vx) | byte(nn)?,
+            ("DRW", [Register(vx), Register(vy), Value(n)]) => 0xd000 | xy(vx, vy) | nibble(n)?,
+            ("SKP", [Register(vx)]) => 0xe09e | x(vx),
+            ("SKNP", [Register(vx)]) => 0xe0a1 | x(vx),
+            ("PLANE", [Value(n)]) => 0xf001 | nibble(n)? << 8,
+            ("AUDIO", []) => 0xf002,
+            ("MOV", [Register(vx), DelayTimer]) => 0xf007 | x(vx),
+            ("MOV", [Register(vx), Key]) => 0xf00a | x(vx),
+            ("MOV", [DelayTimer, Register(vx)]) => 0xf015 | x(vx),
+            ("MOV", [SoundTimer, Register(vx)]) => 0xf018 | x(vx),
+            ("ADD", [Index, Register(vx)]) => 0xf01e | x(vx),
+            ("FONT", [Register(vx)]) => 0xf029 | x(vx),
+            ("HFONT", [Register(vx)]) => 0xf030 | x(vx),
+            ("BCD", [Register(vx)]) => 0xf033 | x(vx),
+            ("PITCH", [Register(vx)]) => 0xf03a | x(vx),
+            ("STR", [IndirectIndex, Register(vx)]) => 0xf055 | x(vx),
+            ("LD", [Register(vx), IndirectIndex]) => 0xf065 | x(vx),
+            ("STR", [Flags, Register(vx)]) => 0xf075 | x(vx),
+            ("LD", [Register(vx), Flags]) => 0xf085 | x(vx),
+            (name, _) if !MNEMONICS.contains(&name) => {
+                return Err(location.error(
+                    mnemonic.column,
+                    format!("Unknown instruction {}", mnemonic.text),
+                ));
+            }
+            _ => {
+                let column = operands
+                    .first()
+                    .map_or(mnemonic.column, |(_, column)| *column);
+                return Err(
+                    location.error(column, format!("Invalid operands for {}", mnemonic.text))
+                );
+            }
+        };
+
+        Ok(vec![opcode])
+    }
+
+    /// Resolves a number, label or constant, which must be at most `max`.
+    fn value(&self, location: &Location, token: &Token, max: u16) -> Result<u16, AssemblyError> {
+        let text = token.text.as_str();
+        let lowercase = text.to_lowercase();
+        let number = if text.is_empty() {
+            return Err(location.error(token.column, "Missing value".to_string()));
+        } else if lowercase.starts_with("0x") {
+            u32::from_str_radix(&text[2..], 16).ok()
+        } else if lowercase.starts_with("0b") {
+            u32::from_str_radix(&text[2..], 2).ok()
+        } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+            text.parse().ok()
+        } else if let Some(value) = self.symbols.get(text) {
+            Some(u32::from(*value))
+        } else {
+            return Err(location.error(token.column, format!("Unknown symbol {}", text)));
+        };
+
+        match number {
+            Some(number) if number <= u32::from(max) => Ok(number as u16),
+            Some(number) => Err(location.error(
+                token.column,
+                format!("{} is larger than {:#x}", number, max),
+            )),
+            None => Err(location.error(token.column, format!("Invalid number {}", text))),
+        }
+    }
+}
+
+const MNEMONICS: [&str; 37] = [
+    "CLS", "RET", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH", "SYS", "JMP", "CALL", "SKE",
+    "SKNE", "SAVE", "LOAD", "MOV", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "RSUB", "SHL", "RND",
+    "DRW", "SKP", "SKNP", "PLANE", "AUDIO", "FONT", "HFONT", "BCD", "PITCH", "STR", "LD",
+];
+
+fn is_symbol(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && parse_register(name).is_none()
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('V'), Some(digit), None) | (Some('v'), Some(digit), None) => {
+            digit.to_digit(16).map(|n| n as u8)
+        }
+        _ => None,
+    }
+}
+
+fn parse_operand(token: Token) -> Operand {
+    if let Some(register) = parse_register(&token.text) {
+        return Operand::Register(register);
+    }
+
+    match token.text.to_uppercase().as_str() {
+        "I" => Operand::Index,
+        "[I]" => Operand::IndirectIndex,
+        "DT" => Operand::DelayTimer,
+        "ST" => Operand::SoundTimer,
+        "K" => Operand::Key,
+        "R" => Operand::Flags,
+        _ => match first_word(&token.text, token.column - 1) {
+            Some(ref word) if word.text.eq_ignore_ascii_case("long") => {
+                let value_start = word.text.len();
+                let value = token.text[value_start..].trim_start();
+                Operand::Long(Token {
+                    text: value.to_string(),
+                    column: token.column + token.text.len() - value.len(),
+                })
+            }
+            _ => Operand::Value(token),
+        },
+    }
+}
+
+/// Returns the first whitespace-separated word of `text`, which starts at the
+/// 0-based column `offset` of its line.
+fn first_word(text: &str, offset: usize) -> Option<Token> {
+    let start = text.find(|c: char| !c.is_whitespace())?;
+    let end = text[start..]
+        .find(char::is_whitespace)
+        .map_or(text.len(), |length| start + length);
+
+    Some(Token {
+        text: text[start..end].to_string(),
+        column: offset + start + 1,
+    })
+}
+
+/// Splits comma-separated operands, trimming the whitespace around each.
+fn split_operands(text: &str, offset: usize) -> Vec<Token> {
+    if text.trim().is_empty() {
+        return Vec::new();
+    }
+
+    let mut operands = Vec::new();
+    let mut start = 0;
+    for part in text.split(',') {
+        let trimmed = part.trim();
+        let leading = part.len() - part.trim_start().len();
+        operands.push(Token {
+            text: trimmed.to_string(),
+            column: offset + start + leading + 1,
+        });
+        start += part.len() + 1;
+    }
+
+    operands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Platform;
+    use crate::disassembler::disassemble;
+
+    fn error_at(source: &str) -> (usize, usize, String) {
+        let error = assemble(source).unwrap_err();
+        (error.line, error.column, error.message)
+    }
+
+    #[test]
+    fn disassembled_roms_reassemble_to_the_same_bytes() {
+        let roms = Path::new(env!("CARGO_MANIFEST_DIR")).join("roms");
+        for entry in fs::read_dir(roms).unwrap() {
+            let path = entry.unwrap().path();
+            let rom = fs::read(&path).unwrap();
+            for platform in &[Platform::Chip8, Platform::SuperChip, Platform::XoChip] {
+                let listing = disassemble(&rom, *platform);
+                let reassembled = assemble(&listing)
+                    .unwrap_or_else(|error| panic!("{}: {}", path.display(), error));
+                assert!(reassembled == rom, "{} changed", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn labels_may_be_used_before_they_are_defined() {
+        let source = "
+            start:  CALL draw
+                    JMP start
+            draw:   MOV I, sprite
+                    RET
+            sprite: db 0xf0
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [0x22, 0x04, 0x12, 0x00, 0xa2, 0x08, 0x00, 0xee, 0xf0]
+        );
+    }
+
+    #[test]
+    fn constants_and_data() {
+        let source = "
+            speed equ 3
+            mask  equ 0b1010
+                    MOV V0, speed
+                    db speed, mask, 0x10
+                    dw 0x1234, speed
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [0x60, 0x03, 0x03, 0x0a, 0x10, 0x12, 0x34, 0x00, 0x03]
+        );
+    }
+
+    #[test]
+    fn errors_have_the_line_and_column() {
+        assert_eq!(
+            error_at("    CLS\n    FOO V0"),
+            (2, 5, "Unknown instruction FOO".to_string())
+        );
+        assert_eq!(
+            error_at("start:\n    JMP nowhere"),
+            (2, 9, "Unknown symbol nowhere".to_string())
+        );
+        assert_eq!(
+            error_at("    MOV V0, 0x100"),
+            (1, 13, "256 is larger than 0xff".to_string())
+        );
+        assert_eq!(
+            error_at("a:\na:"),
+            (2, 1, "a is already defined".to_string())
+        );
+    }
+
+    #[test]
+    fn includes_are_assembled_in_place() {
+        let directory = std::env::temp_dir().join(format!("chip8-asm-{}", std::process::id()));
+        fs::create_dir_all(directory.join("lib")).unwrap();
+        fs::write(
+            directory.join("main.asm"),
+            "    CALL clear\n    include \"lib/clear.asm\"\n",
+        )
+        .unwrap();
+        fs::write(
+            directory.join("lib/clear.asm"),
+            "clear:\n    CLS\n    RET\n",
+        )
+        .unwrap();
+        fs::write(
+            directory.join("broken.asm"),
+            "    CLS\n    include \"lib/bad.asm\"\n",
+        )
+        .unwrap();
+        fs::write(directory.join("lib/bad.asm"), "    CLS\n    RET V0\n").unwrap();
+
+        let binary = assemble_file(&directory.join("main.asm"));
+        let error = assemble_file(&directory.join("broken.asm")).unwrap_err();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(binary.unwrap(), [0x22, 0x02, 0x00, 0xe0, 0x00, 0xee]);
+        assert!(error.file.ends_with("bad.asm"), "{}", error);
+        assert_eq!(
+            (error.line, error.column, error.message.as_str()),
+            (2, 9, "Invalid operands for RET")
+        );
+    }
+}
@@ -0,0 +1,21 @@
+use std::env;
+use std::io;
+use std::path::Path;
+
+use chip8::assembler;
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        println!("usage: chip8-asm <source_path> <output_path>");
+        std::process::exit(1);
+    }
+
+    match assembler::assemble_file(Path::new(&args[1])) {
+        Ok(binary) => std::fs::write(&args[2], binary),
+        Err(error) => {
+            println!("{}", error);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::env;
+use std::path::Path;
+
+use chip8::cpu::Platform;
+use chip8::disassembler;
+use chip8::rom::Rom;
+
+const USAGE: &str = "usage: chip8-disasm [--platform <chip8|schip|xochip>] <program_path>";
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let (platform, path) = match args.as_slice() {
+        [_, path] => (None, path),
+        [_, option, name, path] if option == "--platform" => match Platform::from_name(name) {
+            Some(platform) => (Some(platform), path),
+            None => {
+                println!("--platform must be one of chip8, schip or xochip");
+                std::process::exit(1);
+            }
+        },
+        _ => {
+            println!("{}", USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let rom = match Rom::load(Path::new(path)) {
+        Ok(rom) => rom,
+        Err(error) => {
+            println!("Failed to load {}: {}", path, error);
+            std::process::exit(1);
+        }
+    };
+    // Without --platform, known ROMs are read as the platform they were
+    // written for.
+    let platform = platform
+        .or_else(|| rom.info().and_then(|info| info.platform))
+        .unwrap_or(Platform::Chip8);
+
+    println!("; {}", path);
+    print!("{}", disassembler::disassemble(&rom.bytes, platform));
+}
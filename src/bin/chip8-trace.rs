@@ -0,0 +1,39 @@
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+
+use chip8::trace::{TraceError, TraceReader};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let (full, path) = match args.as_slice() {
+        [_, path] => (false, path),
+        [_, option, path] if option == "--full" => (true, path),
+        _ => {
+            println!("usage: chip8-trace [--full] <trace_path>");
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(error) = print_trace(path, full) {
+        println!("Failed to read trace {}: {}", path, error);
+        std::process::exit(1);
+    }
+}
+
+/// Prints every record of the trace at `path` as a line of text. With `full`,
+/// each line has the complete state before and after the instruction, rather
+/// than only what it changed.
+fn print_trace(path: &str, full: bool) -> Result<(), TraceError> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut reader = TraceReader::new(&mut file)?;
+    println!("; {}", path);
+    while let Some(record) = reader.next_record()? {
+        if full {
+            println!("{}", record.full_state());
+        } else {
+            println!("{}", record);
+        }
+    }
+    Ok(())
+}
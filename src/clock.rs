@@ -0,0 +1,131 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cpu::TIMER_RATE;
+
+pub const DEFAULT_CLOCK_RATE: u32 = 700;
+
+// How far behind schedule the clock may fall before it stops trying to catch up.
+// Without this, a stall (e.g. the process being suspended) would be followed by
+// a burst of instructions executed at full host speed.
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+pub struct Clock {
+    rate: u32,
+    throttled: bool,
+    epoch: Instant,
+    cycles: u64,
+    total_cycles: u64,
+}
+
+impl Clock {
+    pub fn new(rate: u32) -> Clock {
+        Clock {
+            rate,
+            throttled: true,
+            epoch: Instant::now(),
+            cycles: 0,
+            total_cycles: 0,
+        }
+    }
+
+    /// Creates a clock that never sleeps, for running the CPU as fast as the
+    /// host allows while keeping the same frame boundaries.
+    pub fn unthrottled(rate: u32) -> Clock {
+        Clock {
+            throttled: false,
+            ..Clock::new(rate)
+        }
+    }
+
+    /// Blocks until the next cycle is due.
+    ///
+    /// Deadlines are computed from a fixed epoch rather than from the time of
+    /// the previous call, so time spent executing instructions and sleep
+    /// overshoot don't accumulate into drift.
+    pub fn wait(&mut self) {
+        if self.throttled {
+            self.sleep_until_due();
+        }
+
+        self.cycles += 1;
+        self.total_cycles += 1;
+    }
+
+    /// Returns whether the cycle started by the last `wait` crossed into a new
+    /// 60 Hz timer frame.
+    ///
+    /// Frames are derived from the cycle count alone, so the timers advance
+    /// at the same instruction boundaries on every run.
+    pub fn frame_elapsed(&self) -> bool {
+        let frame = |cycles: u64| cycles * u64::from(TIMER_RATE) / u64::from(self.rate);
+        self.total_cycles > 0 && frame(self.total_cycles) != frame(self.total_cycles - 1)
+    }
+
+    fn sleep_until_due(&mut self) {
+        let deadline = self.epoch + self.cycle_offset(self.cycles);
+        let now = Instant::now();
+
+        if deadline > now {
+            thread::sleep(deadline - now);
+        } else if now - deadline > MAX_LAG {
+            self.epoch = now;
+            self.cycles = 0;
+        }
+    }
+
+    fn cycle_offset(&self, cycles: u64) -> Duration {
+        Duration::from_nanos(cycles * 1_000_000_000 / u64::from(self.rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cycles_are_spread_evenly_over_a_second() {
+        let clock = Clock::new(700);
+        assert_eq!(clock.cycle_offset(0), Duration::from_secs(0));
+        assert_eq!(clock.cycle_offset(7), Duration::from_millis(10));
+        assert_eq!(clock.cycle_offset(700), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn wait_throttles_to_the_clock_rate() {
+        let mut clock = Clock::new(1000);
+        let start = Instant::now();
+        for _ in 0..51 {
+            clock.wait();
+        }
+        // The first cycle is due right away, the 51st after 50 ms.
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn wait_gives_up_catching_up_after_a_stall() {
+        let mut clock = Clock::new(1000);
+        clock.epoch -= MAX_LAG * 2;
+        let start = Instant::now();
+        for _ in 0..10 {
+            clock.wait();
+        }
+        // Catching up would have run the 10 cycles without sleeping.
+        assert!(start.elapsed() >= Duration::from_millis(9));
+    }
+
+    #[test]
+    fn frames_elapse_at_60_hz_of_cycles() {
+        let mut clock = Clock::unthrottled(700);
+        let mut frame_ends = Vec::new();
+        for cycle in 1..=700 {
+            clock.wait();
+            if clock.frame_elapsed() {
+                frame_ends.push(cycle);
+            }
+        }
+        assert_eq!(frame_ends.len(), 60);
+        assert_eq!(&frame_ends[..3], [12, 24, 35]);
+        assert_eq!(frame_ends.last(), Some(&700));
+    }
+}
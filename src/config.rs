@@ -0,0 +1,73 @@
+use crate::keymap::Keymap;
+use crate::palette::Palette;
+use std::path::Path;
+use toml::value::{Table, Value};
+
+/// The config file loaded when `--config` isn't given, if it exists.
+pub const DEFAULT_CONFIG_PATH: &str = "chip8.toml";
+
+/// The settings of the frontend read from its TOML config file. See `Keymap`
+/// and `Palette` for their settings.
+pub struct Config {
+    pub keymap: Keymap,
+    pub palette: Palette,
+}
+
+impl Config {
+    /// Loads the config for the ROM at `rom_path` from the file at
+    /// `config_path`. `rom_keys` are the key bindings the ROM database
+    /// suggests.
+    pub fn load(
+        config_path: &Path,
+        rom_path: &Path,
+        rom_keys: &[(usize, String)],
+    ) -> Result<Config, String> {
+        let config = std::fs::read_to_string(config_path)
+            .map_err(|error| format!("{}: {}", config_path.display(), error))?;
+        Config::parse(&config, rom_path, rom_keys)
+            .map_err(|message| format!("{}: {}", config_path.display(), message))
+    }
+
+    /// Parses a config file, reporting every problem in it at once.
+    pub fn parse(
+        config: &str,
+        rom_path: &Path,
+        rom_keys: &[(usize, String)],
+    ) -> Result<Config, String> {
+        let config: Value = config.parse().map_err(|error| format!("{}", error))?;
+        let config = config.as_table().unwrap();
+        let rom_name = rom_path
+            .file_stem()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let mut errors = Vec::new();
+        check_fields(
+            config,
+            &["preset", "keys", "gamepad", "roms", "palette"],
+            "",
+            &mut errors,
+        );
+        let keymap = Keymap::from_config(config, &rom_name, rom_keys, &mut errors);
+        let palette = match config.get("palette") {
+            Some(palette) => Palette::from_config(palette, &mut errors),
+            None => Palette::from_preset("mono").unwrap(),
+        };
+
+        if errors.is_empty() {
+            Ok(Config { keymap, palette })
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+}
+
+/// Reports the fields of `table` that aren't in `fields`. `path` is the
+/// table's location in the config, for error messages.
+pub fn check_fields(table: &Table, fields: &[&str], path: &str, errors: &mut Vec<String>) {
+    for field in table.keys() {
+        if !fields.contains(&field.as_str()) {
+            errors.push(format!("Unknown setting {}{}", path, field));
+        }
+    }
+}
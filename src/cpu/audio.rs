@@ -0,0 +1,455 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use rodio::source::Source;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+pub const PATTERN_SIZE: usize = 16;
+
+const OUTPUT_SAMPLE_RATE: u32 = 44100;
+
+/// The samples in one 60 Hz frame.
+const SAMPLES_PER_FRAME: u32 = OUTPUT_SAMPLE_RATE / super::TIMER_RATE;
+
+pub trait AudioSink {
+    /// Lets the tone through, until `close_gate` is called or for `duration`.
+    fn open_gate(&mut self, duration: Option<Duration>);
+    fn close_gate(&mut self);
+    /// Replaces the beep with an XO-CHIP audio pattern: 128 1-bit samples
+    /// played back at `sample_rate` Hz.
+    fn set_pattern(&mut self, _pattern: [u8; PATTERN_SIZE], _sample_rate: f32) {}
+    /// Called once at the end of every 60 Hz frame.
+    fn end_frame(&mut self) {}
+}
+
+/// Converts an XO-CHIP pitch register value to a pattern playback rate.
+pub fn pitch_to_sample_rate(pitch: u8) -> f32 {
+    4000.0 * 2f32.powf((f32::from(pitch) - 64.0) / 48.0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+}
+
+/// The beep played while the sound timer is running, unless the program
+/// loaded an audio pattern.
+#[derive(Clone, Copy, Debug)]
+pub struct ToneSettings {
+    pub waveform: Waveform,
+    pub frequency: f32,
+    /// From 0 to 1.
+    pub volume: f32,
+}
+
+impl Default for ToneSettings {
+    fn default() -> ToneSettings {
+        ToneSettings {
+            waveform: Waveform::Sine,
+            frequency: 280.0,
+            volume: 0.25,
+        }
+    }
+}
+
+enum Gate {
+    Closed,
+    Open,
+    OpenUntil(Instant),
+    /// Open for this many more output samples.
+    OpenFor(u32),
+}
+
+/// Returns a gate open until `close_gate` is called or for `duration` of real
+/// time.
+fn open_gate(duration: Option<Duration>) -> Gate {
+    match duration {
+        Some(duration) => Gate::OpenUntil(Instant::now() + duration),
+        None => Gate::Open,
+    }
+}
+
+/// A tone generator that runs continuously, silent while its gate is closed.
+struct Tone {
+    settings: ToneSettings,
+    gate: Gate,
+    /// The position in the current period of the beep, from 0 to 1.
+    phase: f32,
+    pattern: Option<Pattern>,
+}
+
+struct Pattern {
+    bits: [u8; PATTERN_SIZE],
+    /// How many bits to advance per output sample.
+    step: f32,
+    position: f32,
+}
+
+impl Tone {
+    fn new(settings: ToneSettings) -> Tone {
+        Tone {
+            settings,
+            gate: Gate::Closed,
+            phase: 0.0,
+            pattern: None,
+        }
+    }
+
+    fn set_pattern(&mut self, bits: [u8; PATTERN_SIZE], sample_rate: f32) {
+        let position = self
+            .pattern
+            .as_ref()
+            .map_or(0.0, |pattern| pattern.position);
+        self.pattern = Some(Pattern {
+            bits,
+            step: sample_rate / OUTPUT_SAMPLE_RATE as f32,
+            position,
+        });
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        let open = match self.gate {
+            Gate::Closed => false,
+            Gate::Open => true,
+            Gate::OpenUntil(deadline) => Instant::now() < deadline,
+            Gate::OpenFor(0) => false,
+            Gate::OpenFor(ref mut samples) => {
+                *samples -= 1;
+                true
+            }
+        };
+
+        let level = match &mut self.pattern {
+            Some(pattern) => {
+                let bit = pattern.position as usize;
+                let lit = pattern.bits[bit / 8] & (0x80 >> (bit % 8)) != 0;
+                pattern.position = (pattern.position + pattern.step) % (PATTERN_SIZE * 8) as f32;
+                if lit {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            None => {
+                let level = match self.settings.waveform {
+                    Waveform::Square if self.phase < 0.5 => 1.0,
+                    Waveform::Square => -1.0,
+                    Waveform::Sine => (2.0 * PI * self.phase).sin(),
+                };
+                self.phase =
+                    (self.phase + self.settings.frequency / OUTPUT_SAMPLE_RATE as f32) % 1.0;
+                level
+            }
+        };
+
+        if open {
+            level * self.settings.volume
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A change to the tone, handed from the CPU thread to rodio's mixer thread.
+enum ToneChange {
+    Gate(Gate),
+    Pattern([u8; PATTERN_SIZE], f32),
+}
+
+pub struct RodioSink {
+    changes: Sender<ToneChange>,
+    // Plays the tone for as long as the sink lives.
+    _sink: rodio::Sink,
+}
+
+impl RodioSink {
+    /// Returns `None` when the host has no audio output device.
+    pub fn new(settings: ToneSettings) -> Option<RodioSink> {
+        let sink = rodio::Sink::new(&rodio::default_output_device()?);
+        let (changes, receiver) = mpsc::channel();
+        sink.append(SharedTone {
+            tone: Tone::new(settings),
+            changes: receiver,
+        });
+        sink.play();
+        Some(RodioSink {
+            changes,
+            _sink: sink,
+        })
+    }
+}
+
+impl AudioSink for RodioSink {
+    fn open_gate(&mut self, duration: Option<Duration>) {
+        let _ = self.changes.send(ToneChange::Gate(open_gate(duration)));
+    }
+
+    fn close_gate(&mut self) {
+        let _ = self.changes.send(ToneChange::Gate(Gate::Closed));
+    }
+
+    fn set_pattern(&mut self, pattern: [u8; PATTERN_SIZE], sample_rate: f32) {
+        let _ = self.changes.send(ToneChange::Pattern(pattern, sample_rate));
+    }
+}
+
+/// Feeds the tone to rodio's mixer thread, which owns it, and applies the
+/// changes the CPU thread sends before each sample. Receiving from a channel
+/// doesn't block, so the mixer never waits for the CPU thread.
+struct SharedTone {
+    tone: Tone,
+    changes: Receiver<ToneChange>,
+}
+
+impl Iterator for SharedTone {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        for change in self.changes.try_iter() {
+            match change {
+                ToneChange::Gate(gate) => self.tone.gate = gate,
+                ToneChange::Pattern(pattern, sample_rate) => {
+                    self.tone.set_pattern(pattern, sample_rate)
+                }
+            }
+        }
+        Some(self.tone.next_sample())
+    }
+}
+
+impl Source for SharedTone {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        OUTPUT_SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Writes the sound to a 16-bit mono WAV file, one frame's worth of samples
+/// at the end of every frame, so that it follows emulated time and needs no
+/// sound card. The sizes in the header are only filled in when the sink is
+/// dropped.
+pub struct WavSink {
+    tone: Tone,
+    writer: BufWriter<File>,
+    sample_count: u32,
+}
+
+impl WavSink {
+    pub fn create(path: &Path, settings: ToneSettings) -> io::Result<WavSink> {
+        let mut sink = WavSink {
+            tone: Tone::new(settings),
+            writer: BufWriter::new(File::create(path)?),
+            sample_count: 0,
+        };
+        sink.write_header()?;
+        Ok(sink)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let data_size = self.sample_count * 2;
+        let writer = &mut self.writer;
+        writer.write_all(b"RIFF")?;
+        writer.write_u32::<LittleEndian>(36 + data_size)?;
+        writer.write_all(b"WAVEfmt ")?;
+        writer.write_u32::<LittleEndian>(16)?;
+        // PCM, mono.
+        writer.write_u16::<LittleEndian>(1)?;
+        writer.write_u16::<LittleEndian>(1)?;
+        writer.write_u32::<LittleEndian>(OUTPUT_SAMPLE_RATE)?;
+        writer.write_u32::<LittleEndian>(OUTPUT_SAMPLE_RATE * 2)?;
+        writer.write_u16::<LittleEndian>(2)?;
+        writer.write_u16::<LittleEndian>(16)?;
+        writer.write_all(b"data")?;
+        writer.write_u32::<LittleEndian>(data_size)
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        for _ in 0..SAMPLES_PER_FRAME {
+            let sample = self.tone.next_sample() * f32::from(i16::MAX);
+            self.writer.write_i16::<LittleEndian>(sample as i16)?;
+        }
+        self.sample_count += SAMPLES_PER_FRAME;
+        Ok(())
+    }
+
+    /// Fills in the sizes in the header, now that all the samples are
+    /// written.
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.writer.flush()
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if let Err(error) = self.finish() {
+            println!("Failed to write audio: {}", error);
+        }
+    }
+}
+
+impl AudioSink for WavSink {
+    /// Counts a timed gate down in samples written rather than real time, so
+    /// that the file doesn't depend on how fast the host runs.
+    fn open_gate(&mut self, duration: Option<Duration>) {
+        self.tone.gate = match duration {
+            Some(duration) => {
+                Gate::OpenFor((duration.as_secs_f64() * f64::from(OUTPUT_SAMPLE_RATE)) as u32)
+            }
+            None => Gate::Open,
+        };
+    }
+
+    fn close_gate(&mut self) {
+        self.tone.gate = Gate::Closed;
+    }
+
+    fn set_pattern(&mut self, pattern: [u8; PATTERN_SIZE], sample_rate: f32) {
+        self.tone.set_pattern(pattern, sample_rate);
+    }
+
+    fn end_frame(&mut self) {
+        if let Err(error) = self.write_frame() {
+            println!("Failed to write audio: {}", error);
+        }
+    }
+}
+
+/// Discards all sound, for machines without an audio device or when muted.
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn open_gate(&mut self, _duration: Option<Duration>) {}
+
+    fn close_gate(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ReadBytesExt;
+    use std::io::Read;
+
+    fn square_tone() -> Tone {
+        Tone::new(ToneSettings {
+            waveform: Waveform::Square,
+            frequency: 441.0,
+            volume: 0.5,
+        })
+    }
+
+    fn sounding_samples(tone: &mut Tone, samples: usize) -> usize {
+        (0..samples).filter(|_| tone.next_sample() != 0.0).count()
+    }
+
+    #[test]
+    fn closed_gate_is_silent() {
+        let mut tone = square_tone();
+        assert_eq!(sounding_samples(&mut tone, 1000), 0);
+    }
+
+    #[test]
+    fn open_gate_sounds_until_closed() {
+        let mut tone = square_tone();
+        tone.gate = Gate::Open;
+        assert_eq!(tone.next_sample(), 0.5);
+        assert_eq!(sounding_samples(&mut tone, 1000), 1000);
+
+        tone.gate = Gate::Closed;
+        assert_eq!(sounding_samples(&mut tone, 1000), 0);
+    }
+
+    #[test]
+    fn gate_open_for_a_number_of_samples_closes_after_them() {
+        let mut tone = square_tone();
+        tone.gate = Gate::OpenFor(300);
+        assert_eq!(sounding_samples(&mut tone, 1000), 300);
+    }
+
+    #[test]
+    fn gate_open_until_a_deadline_closes_after_it() {
+        let mut tone = square_tone();
+        tone.gate = open_gate(Some(Duration::from_secs(60)));
+        assert_eq!(sounding_samples(&mut tone, 1000), 1000);
+
+        tone.gate = Gate::OpenUntil(Instant::now());
+        assert_eq!(sounding_samples(&mut tone, 1000), 0);
+    }
+
+    #[test]
+    fn pattern_replaces_the_tone() {
+        let mut tone = square_tone();
+        tone.gate = Gate::Open;
+        // Alternating bits, one per output sample.
+        tone.set_pattern([0xaa; PATTERN_SIZE], OUTPUT_SAMPLE_RATE as f32);
+        let samples: Vec<f32> = (0..4).map(|_| tone.next_sample()).collect();
+        assert_eq!(samples, [0.5, -0.5, 0.5, -0.5]);
+    }
+
+    #[test]
+    fn wav_header_describes_the_samples_written() {
+        let path = std::env::temp_dir().join(format!("chip8-test-{}.wav", std::process::id()));
+        let mut sink = WavSink::create(&path, ToneSettings::default()).unwrap();
+        // A timed gate counts down in samples, so two and a half frames sound.
+        sink.open_gate(Some(Duration::from_millis(1000 * 5 / 2 / 60)));
+        for _ in 0..4 {
+            sink.end_frame();
+        }
+        drop(sink);
+
+        let mut file = File::open(&path).unwrap();
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let data_size = 4 * SAMPLES_PER_FRAME * 2;
+        let mut header = &bytes[..44];
+        let tag = |header: &mut &[u8]| {
+            let mut tag = [0; 4];
+            header.read_exact(&mut tag).unwrap();
+            tag
+        };
+        assert_eq!(&tag(&mut header), b"RIFF");
+        assert_eq!(header.read_u32::<LittleEndian>().unwrap(), 36 + data_size);
+        assert_eq!(&tag(&mut header), b"WAVE");
+        assert_eq!(&tag(&mut header), b"fmt ");
+        assert_eq!(header.read_u32::<LittleEndian>().unwrap(), 16);
+        assert_eq!(header.read_u16::<LittleEndian>().unwrap(), 1);
+        assert_eq!(header.read_u16::<LittleEndian>().unwrap(), 1);
+        assert_eq!(
+            header.read_u32::<LittleEndian>().unwrap(),
+            OUTPUT_SAMPLE_RATE
+        );
+        assert_eq!(
+            header.read_u32::<LittleEndian>().unwrap(),
+            OUTPUT_SAMPLE_RATE * 2
+        );
+        assert_eq!(header.read_u16::<LittleEndian>().unwrap(), 2);
+        assert_eq!(header.read_u16::<LittleEndian>().unwrap(), 16);
+        assert_eq!(&tag(&mut header), b"data");
+        assert_eq!(header.read_u32::<LittleEndian>().unwrap(), data_size);
+        assert_eq!(bytes.len(), 44 + data_size as usize);
+
+        let mut samples = &bytes[44..];
+        let sounding = (0..4 * SAMPLES_PER_FRAME)
+            .filter(|_| samples.read_i16::<LittleEndian>().unwrap() != 0)
+            .count() as u32;
+        assert!(sounding > 2 * SAMPLES_PER_FRAME && sounding <= 5 * SAMPLES_PER_FRAME / 2);
+    }
+}
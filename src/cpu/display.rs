@@ -1,25 +1,52 @@
-use super::user_interface::{DISPLAY_HEIGHT, DISPLAY_WIDTH, UI};
+use super::user_interface::{PLANE_COUNT, UI};
 use bitvec::{BigEndian, BitVec, Bits};
 
 type Sprite = Vec<BitVec>;
 
-pub fn draw_sprite(ui: &mut UI, x: usize, y: usize, sprite_data: &[u8]) -> bool {
+/// Draws a sprite whose rows are `row_size` bytes wide into each plane
+/// selected by the `planes` bitmask. When several planes are selected,
+/// `sprite_data` holds one sprite per plane, back to back. The sprite's origin
+/// always wraps around the display, while the pixels past its edges are either
+/// clipped or wrapped depending on `clip`. Returns whether any lit pixel was
+/// erased.
+pub fn draw_sprite(
+    ui: &mut dyn UI,
+    x: usize,
+    y: usize,
+    sprite_data: &[u8],
+    row_size: usize,
+    planes: u8,
+    clip: bool,
+) -> bool {
+    if sprite_data.is_empty() || planes == 0 {
+        return false;
+    }
+
+    let (width, height) = ui.resolution();
+    let (x, y) = (x % width, y % height);
     let mut collision: bool = false;
-    let sprite = generate_sprite(sprite_data);
+    let plane_size = sprite_data.len() / planes.count_ones() as usize;
+    let selected_planes = (0..PLANE_COUNT).filter(|plane| planes & (1 << plane) != 0);
+
+    for (plane, plane_data) in selected_planes.zip(sprite_data.chunks(plane_size)) {
+        let mask = 1 << plane;
+        let sprite = generate_sprite(plane_data, row_size);
+
+        for (line, sprite_line) in sprite.iter().enumerate() {
+            for (column, pixel) in sprite_line.iter().enumerate() {
+                if !pixel || (clip && (x + column >= width || y + line >= height)) {
+                    continue;
+                }
 
-    for line in y..y + sprite.len() {
-        for column in x..x + 8 {
-            let old_pixel_value = ui.read_pixel(line % DISPLAY_HEIGHT, column % DISPLAY_WIDTH);
-            let pixel = sprite[line - y][column - x];
+                let pixel_x = (x + column) % width;
+                let pixel_y = (y + line) % height;
+                let old_pixel_value = ui.read_pixel(pixel_x, pixel_y);
 
-            ui.write_pixel(
-                line % DISPLAY_HEIGHT,
-                column % DISPLAY_WIDTH,
-                old_pixel_value ^ pixel,
-            );
+                ui.write_pixel(pixel_x, pixel_y, old_pixel_value ^ mask);
 
-            if old_pixel_value && pixel {
-                collision = true;
+                if old_pixel_value & mask != 0 {
+                    collision = true;
+                }
             }
         }
     }
@@ -27,12 +54,12 @@ pub fn draw_sprite(ui: &mut UI, x: usize, y: usize, sprite_data: &[u8]) -> bool
     collision
 }
 
-fn generate_sprite(sprite_data: &[u8]) -> Sprite {
+fn generate_sprite(sprite_data: &[u8], row_size: usize) -> Sprite {
     sprite_data
-        .iter()
-        .map(|byte| {
-            (0..8)
-                .map(move |bit_index| byte.get::<BigEndian>(bit_index.into()))
+        .chunks(row_size)
+        .map(|row| {
+            row.iter()
+                .flat_map(|byte| (0..8).map(move |bit_index| byte.get::<BigEndian>(bit_index.into())))
                 .collect()
         })
         .collect()
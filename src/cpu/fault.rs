@@ -0,0 +1,125 @@
+use super::memory::OutOfBounds;
+use std::fmt;
+
+/// What happened when the CPU executed an instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StepOutcome {
+    Executed,
+    /// `MOV Vx, K` found no key pressed, and will execute again.
+    WaitingForKey,
+    /// The program stopped the interpreter with `EXIT`.
+    Halted,
+}
+
+/// An error that stops the program, such as an instruction the platform
+/// doesn't have. `address` is where the faulting instruction is, and `opcode`
+/// is the instruction itself, or 0 when it couldn't even be fetched.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CpuFault {
+    IllegalOpcode {
+        address: usize,
+        opcode: u16,
+    },
+    /// The instruction accessed memory past its end, starting at `access`.
+    MemoryOutOfBounds {
+        address: usize,
+        opcode: u16,
+        access: usize,
+    },
+    /// A call nested deeper than the stack holds.
+    StackOverflow {
+        address: usize,
+        opcode: u16,
+    },
+    /// A return without a matching call.
+    StackUnderflow {
+        address: usize,
+        opcode: u16,
+    },
+    /// A `SYS` call to machine code with no routine to stand in for it, under
+    /// `SysMode::Fault`.
+    MachineCodeCall {
+        address: usize,
+        opcode: u16,
+    },
+    /// The ROM doesn't fit in the platform's memory.
+    RomTooLarge {
+        size: usize,
+        max_size: usize,
+    },
+}
+
+impl fmt::Display for CpuFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            CpuFault::IllegalOpcode { address, opcode } => {
+                write!(f, "Illegal opcode {:04x} at {:#06x}", opcode, address)
+            }
+            CpuFault::MemoryOutOfBounds {
+                address,
+                opcode,
+                access,
+            } => write!(
+                f,
+                "Memory access out of bounds at {:#06x} by opcode {:04x} at {:#06x}",
+                access, opcode, address
+            ),
+            CpuFault::StackOverflow { address, opcode } => {
+                write!(
+                    f,
+                    "Stack overflow by opcode {:04x} at {:#06x}",
+                    opcode, address
+                )
+            }
+            CpuFault::StackUnderflow { address, opcode } => {
+                write!(
+                    f,
+                    "Stack underflow by opcode {:04x} at {:#06x}",
+                    opcode, address
+                )
+            }
+            CpuFault::MachineCodeCall { address, opcode } => write!(
+                f,
+                "Call to machine code by opcode {:04x} at {:#06x}",
+                opcode, address
+            ),
+            CpuFault::RomTooLarge { size, max_size } => write!(
+                f,
+                "The ROM is {} bytes, but at most {} bytes fit in memory",
+                size, max_size
+            ),
+        }
+    }
+}
+
+/// A fault detected while executing an instruction, before it is attributed
+/// to the instruction.
+pub(super) enum Fault {
+    IllegalOpcode,
+    OutOfBounds(usize),
+    StackOverflow,
+    StackUnderflow,
+    MachineCodeCall,
+}
+
+impl From<OutOfBounds> for Fault {
+    fn from(OutOfBounds(access): OutOfBounds) -> Fault {
+        Fault::OutOfBounds(access)
+    }
+}
+
+impl Fault {
+    pub(super) fn at(self, address: usize, opcode: u16) -> CpuFault {
+        match self {
+            Fault::IllegalOpcode => CpuFault::IllegalOpcode { address, opcode },
+            Fault::OutOfBounds(access) => CpuFault::MemoryOutOfBounds {
+                address,
+                opcode,
+                access,
+            },
+            Fault::StackOverflow => CpuFault::StackOverflow { address, opcode },
+            Fault::StackUnderflow => CpuFault::StackUnderflow { address, opcode },
+            Fault::MachineCodeCall => CpuFault::MachineCodeCall { address, opcode },
+        }
+    }
+}
@@ -1,20 +1,39 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::io::prelude::*;
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::BTreeSet;
+use std::ops::Range;
 
-pub struct Memory(pub Vec<u8>);
+/// An access that runs past the end of memory, starting at the given address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutOfBounds(pub usize);
 
-const MEMORY_SIZE: usize = 0x1000;
+pub struct Memory {
+    bytes: Vec<u8>,
+    watchpoints: BTreeSet<usize>,
+    watch_hits: Vec<usize>,
+}
+
+pub const MEMORY_SIZE: usize = 0x1000;
+pub const XO_MEMORY_SIZE: usize = 0x10000;
 
 const FONTS_BASE: usize = 0;
 const FONT_SIZE: usize = 5;
 const FONT_COUNT: usize = 16;
 
+pub const BIG_FONTS_BASE: usize = FONTS_BASE + FONT_SIZE * FONT_COUNT;
+pub const BIG_FONT_SIZE: usize = 10;
+
 pub const PROGRAM_CODE_BASE: usize = 0x200;
 
 pub const STACK_BASE: usize = 0xefe;
 
 pub const WORD_SIZE: usize = 2;
 
+/// The number of return addresses the stack holds. As in the original
+/// interpreter, the stack grows down over memory the program doesn't use, so
+/// programs that leave subroutines without returning keep running until the
+/// stack reaches `PROGRAM_CODE_BASE`.
+pub const STACK_DEPTH: usize = (STACK_BASE - PROGRAM_CODE_BASE) / WORD_SIZE;
+
 const FONTS: [u8; FONT_SIZE * FONT_COUNT] = [
     0xf0, 0x90, 0x90, 0x90, 0xf0, // 0
     0x20, 0x60, 0x20, 0x20, 0x70, // 1
@@ -34,36 +53,117 @@ const FONTS: [u8; FONT_SIZE * FONT_COUNT] = [
     0xf0, 0x80, 0xf0, 0x80, 0x80, // F
 ];
 
+// The SUPER-CHIP 8x10 font, extended with the A-F digits that SCHIP 1.1 lacks.
+const BIG_FONTS: [u8; BIG_FONT_SIZE * FONT_COUNT] = [
+    0xff, 0xff, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xff, 0xff, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xff, 0xff, // 1
+    0xff, 0xff, 0x03, 0x03, 0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, // 2
+    0xff, 0xff, 0x03, 0x03, 0xff, 0xff, 0x03, 0x03, 0xff, 0xff, // 3
+    0xc3, 0xc3, 0xc3, 0xc3, 0xff, 0xff, 0x03, 0x03, 0x03, 0x03, // 4
+    0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0x03, 0x03, 0xff, 0xff, // 5
+    0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff, // 6
+    0xff, 0xff, 0x03, 0x03, 0x06, 0x0c, 0x18, 0x18, 0x18, 0x18, // 7
+    0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff, // 8
+    0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff, 0x03, 0x03, 0xff, 0xff, // 9
+    0x7e, 0xff, 0xc3, 0xc3, 0xc3, 0xff, 0xff, 0xc3, 0xc3, 0xc3, // A
+    0xfc, 0xfc, 0xc3, 0xc3, 0xfc, 0xfc, 0xc3, 0xc3, 0xfc, 0xfc, // B
+    0x3c, 0xff, 0xc3, 0xc0, 0xc0, 0xc0, 0xc0, 0xc3, 0xff, 0x3c, // C
+    0xfc, 0xfe, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xfe, 0xfc, // D
+    0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, // E
+    0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0xc0, 0xc0, 0xc0, 0xc0, // F
+];
+
 impl Memory {
-    pub fn new(program_code: &[u8]) -> Memory {
-        let mut memory = vec![0; MEMORY_SIZE];
+    pub fn new(program_code: &[u8], size: usize) -> Memory {
+        let mut memory = vec![0; size];
         memory.splice(
             FONTS_BASE..(FONTS_BASE + FONT_SIZE * FONT_COUNT),
             FONTS.iter().cloned(),
         );
+        memory.splice(
+            BIG_FONTS_BASE..(BIG_FONTS_BASE + BIG_FONT_SIZE * FONT_COUNT),
+            BIG_FONTS.iter().cloned(),
+        );
         memory.splice(
             PROGRAM_CODE_BASE..(PROGRAM_CODE_BASE + program_code.len()),
             program_code.iter().cloned(),
         );
 
-        Memory(memory)
+        Memory {
+            bytes: memory,
+            watchpoints: BTreeSet::new(),
+            watch_hits: Vec::new(),
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Replaces the whole contents of memory, keeping the watchpoints.
+    pub fn restore(&mut self, bytes: &[u8]) {
+        self.bytes = bytes.to_vec();
+    }
+
+    /// Returns `size` bytes of memory starting at `offset`.
+    pub fn slice(&self, offset: usize, size: usize) -> Result<&[u8], OutOfBounds> {
+        let range = self.range(offset, size)?;
+        Ok(&self.bytes[range])
     }
 
-    pub fn read_at(&self, buf: &mut [u8], offset: usize) {
-        (&self.0[offset..]).read_exact(buf).unwrap()
+    pub fn read_at(&self, buf: &mut [u8], offset: usize) -> Result<(), OutOfBounds> {
+        buf.copy_from_slice(self.slice(offset, buf.len())?);
+        Ok(())
     }
 
-    pub fn write_at(&mut self, buf: &[u8], offset: usize) {
-        (&mut self.0[offset..]).write_all(buf).unwrap()
+    pub fn write_at(&mut self, buf: &[u8], offset: usize) -> Result<(), OutOfBounds> {
+        let range = self.range(offset, buf.len())?;
+        self.check_watchpoints(offset, buf.len());
+        self.bytes[range].copy_from_slice(buf);
+        Ok(())
     }
 
-    pub fn read_u16_at(&self, offset: usize) -> u16 {
-        (&self.0[offset..]).read_u16::<BigEndian>().unwrap()
+    pub fn read_u16_at(&self, offset: usize) -> Result<u16, OutOfBounds> {
+        Ok(BigEndian::read_u16(self.slice(offset, WORD_SIZE)?))
+    }
+
+    pub fn write_u16_at(&mut self, value: u16, offset: usize) -> Result<(), OutOfBounds> {
+        let range = self.range(offset, WORD_SIZE)?;
+        self.check_watchpoints(offset, WORD_SIZE);
+        BigEndian::write_u16(&mut self.bytes[range], value);
+        Ok(())
+    }
+
+    pub fn watchpoints(&self) -> &BTreeSet<usize> {
+        &self.watchpoints
+    }
+
+    pub fn watch(&mut self, address: usize) {
+        self.watchpoints.insert(address);
+    }
+
+    pub fn unwatch(&mut self, address: usize) {
+        self.watchpoints.remove(&address);
+    }
+
+    /// Returns the watched addresses written to since the last call.
+    pub fn take_watch_hits(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.watch_hits)
+    }
+
+    fn check_watchpoints(&mut self, offset: usize, size: usize) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+
+        let hits = self.watchpoints.range(offset..offset + size);
+        self.watch_hits.extend(hits);
     }
 
-    pub fn write_u16_at(&mut self, value: u16, offset: usize) {
-        (&mut self.0[offset..])
-            .write_u16::<BigEndian>(value)
-            .unwrap()
+    fn range(&self, offset: usize, size: usize) -> Result<Range<usize>, OutOfBounds> {
+        match offset.checked_add(size) {
+            Some(end) if end <= self.bytes.len() => Ok(offset..end),
+            _ => Err(OutOfBounds(offset)),
+        }
     }
 }
@@ -1,302 +1,795 @@
 use bitvec::Bits;
-use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
 
 #[macro_use]
-mod opcode;
-use opcode::Opcode;
+pub mod opcode;
+use opcode::{Instruction, Opcode};
 
-mod memory;
+pub mod audio;
+use audio::{AudioSink, PATTERN_SIZE};
+
+mod fault;
+use fault::Fault;
+pub use fault::{CpuFault, StepOutcome};
+
+pub mod memory;
 
 mod display;
 
+mod snapshot;
+pub use snapshot::{hash_rom, RomHash, Snapshot, SnapshotError};
+
+pub mod quirks;
+pub use quirks::{Quirks, Stack};
+
+pub mod random;
+use random::RandomSource;
+
+pub mod sys;
+pub use sys::SysMode;
+use sys::{SysContext, SysRoutine};
+
 mod timers;
 use timers::{DelayTimer, SoundTimer};
+pub use timers::{TimerMode, TIMER_RATE};
 
 pub mod user_interface;
-use user_interface::UI;
+use user_interface::{HIRES_HEIGHT, HIRES_WIDTH, LORES_HEIGHT, LORES_WIDTH, UI};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Platform {
+    /// The original COSMAC VIP instruction set.
+    Chip8,
+    /// SUPER-CHIP 1.1, adding the 128x64 mode, scrolling, 16x16 sprites, the
+    /// large font and the RPL user flags.
+    SuperChip,
+    /// XO-CHIP, extending SUPER-CHIP with 64 KiB of memory, two bitplanes,
+    /// audio patterns and register range loads and stores.
+    XoChip,
+}
+
+impl Platform {
+    /// Returns the number that identifies the platform in save states and
+    /// movies.
+    pub fn id(self) -> u8 {
+        match self {
+            Platform::Chip8 => 0,
+            Platform::SuperChip => 1,
+            Platform::XoChip => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Platform> {
+        match id {
+            0 => Some(Platform::Chip8),
+            1 => Some(Platform::SuperChip),
+            2 => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+
+    /// Parses the name the command line and the ROM database use.
+    pub fn from_name(name: &str) -> Option<Platform> {
+        match name {
+            "chip8" => Some(Platform::Chip8),
+            "schip" => Some(Platform::SuperChip),
+            "xochip" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+
+    /// Returns the size of the largest ROM that fits in the platform's
+    /// memory, past the interpreter's reserved area.
+    pub fn max_rom_size(self) -> usize {
+        self.memory_size() - memory::PROGRAM_CODE_BASE
+    }
+
+    fn has_superchip(self) -> bool {
+        self != Platform::Chip8
+    }
+
+    fn has_xochip(self) -> bool {
+        self == Platform::XoChip
+    }
+
+    fn memory_size(self) -> usize {
+        if self.has_xochip() {
+            memory::XO_MEMORY_SIZE
+        } else {
+            memory::MEMORY_SIZE
+        }
+    }
+}
+
+const LONG_LOAD_OPCODE: u16 = 0xf000;
+
+/// A copy of the CPU's registers, for inspecting a running program.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub gpr: [u8; 16],
+    pub program_counter: usize,
+    pub index: usize,
+    /// The address of the top of the stack in memory, or the number of
+    /// return addresses on a dedicated stack.
+    pub stack_pointer: usize,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "PC={:#06x} I={:#06x} SP={:#06x} DT={} ST={}",
+            self.program_counter, self.index, self.stack_pointer, self.delay_timer, self.sound_timer
+        )?;
+        for (reg, value) in self.gpr.iter().enumerate() {
+            let separator = if reg == self.gpr.len() - 1 { "" } else { " " };
+            write!(f, "V{:X}={:02x}{}", reg, value, separator)?;
+        }
+        Ok(())
+    }
+}
 
 pub struct Cpu<T: UI> {
+    platform: Platform,
+    rom_hash: RomHash,
+    quirks: Quirks,
     gpr: [u8; 16],
+    rpl_flags: [u8; 16],
     program_counter: usize,
     index: usize,
     stack_pointer: usize,
+    /// The return addresses of a dedicated stack, bottom first. Empty when
+    /// the stack is in memory.
+    stack: Vec<u16>,
     memory: memory::Memory,
     ui: T,
-    rng: rand::rngs::ThreadRng,
+    random: Box<dyn RandomSource>,
     delay_timer: DelayTimer,
     sound_timer: SoundTimer,
+    planes: u8,
+    audio_pattern: [u8; PATTERN_SIZE],
+    pitch: u8,
+    halted: bool,
+    sys_mode: SysMode,
+    sys_routines: HashMap<usize, SysRoutine<T>>,
 }
 
 impl<T: UI> Cpu<T> {
-    pub fn new(rom: Vec<u8>, ui: T) -> Cpu<T> {
-        Cpu {
+    pub fn new(
+        rom: Vec<u8>,
+        ui: T,
+        platform: Platform,
+        quirks: Quirks,
+        timer_mode: TimerMode,
+        audio: Box<dyn AudioSink>,
+        random: Box<dyn RandomSource>,
+    ) -> Result<Cpu<T>, CpuFault> {
+        let max_size = platform.max_rom_size();
+        if rom.len() > max_size {
+            return Err(CpuFault::RomTooLarge {
+                size: rom.len(),
+                max_size,
+            });
+        }
+
+        let (stack, stack_pointer) = match quirks.stack {
+            Stack::Memory => (Vec::new(), memory::STACK_BASE),
+            Stack::Dedicated(depth) => (vec![0; depth], 0),
+        };
+
+        Ok(Cpu {
+            platform,
+            rom_hash: hash_rom(&rom),
+            quirks,
             gpr: [0; 16],
+            rpl_flags: [0; 16],
             program_counter: memory::PROGRAM_CODE_BASE,
             index: 0,
-            stack_pointer: memory::STACK_BASE,
-            memory: memory::Memory::new(&rom),
-            ui: ui,
-            rng: rand::thread_rng(),
-            delay_timer: DelayTimer::new(),
-            sound_timer: SoundTimer::new(),
+            stack_pointer,
+            stack,
+            memory: memory::Memory::new(&rom, platform.memory_size()),
+            ui,
+            random,
+            delay_timer: DelayTimer::new(timer_mode),
+            sound_timer: SoundTimer::new(timer_mode, audio),
+            planes: 1,
+            audio_pattern: [0; PATTERN_SIZE],
+            pitch: 64,
+            halted: false,
+            sys_mode: SysMode::Ignore,
+            sys_routines: HashMap::new(),
+        })
+    }
+
+    /// Sets what `SYS addr` does when no routine is registered at its
+    /// address.
+    pub fn set_sys_mode(&mut self, mode: SysMode) {
+        self.sys_mode = mode;
+    }
+
+    /// Runs `routine` whenever the program calls the machine-code routine at
+    /// `address` with `SYS`.
+    pub fn register_sys_routine(&mut self, address: usize, routine: SysRoutine<T>) {
+        self.sys_routines.insert(address, routine);
+    }
+
+    /// Returns whether the program has stopped the interpreter with `EXIT`.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Ends a 60 Hz frame: advances the delay and sound timers by one tick
+    /// and notifies the UI.
+    pub fn end_frame(&mut self) {
+        self.delay_timer.tick();
+        self.sound_timer.tick();
+        self.ui.end_frame();
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            gpr: self.gpr,
+            program_counter: self.program_counter,
+            index: self.index,
+            stack_pointer: self.stack_pointer,
+            delay_timer: self.delay_timer.get(),
+            sound_timer: self.sound_timer.get(),
+        }
+    }
+
+    /// Captures the complete state of the machine.
+    pub fn snapshot(&self) -> Snapshot {
+        let (width, height) = self.ui.resolution();
+        let mut display = user_interface::Screen::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                display.set(x, y, self.ui.read_pixel(x, y));
+            }
+        }
+
+        let mut keypad = [false; 16];
+        for (key_code, pressed) in keypad.iter_mut().enumerate() {
+            *pressed = self.ui.is_key_pressed(key_code);
+        }
+
+        Snapshot {
+            rom_hash: self.rom_hash,
+            platform: self.platform,
+            gpr: self.gpr,
+            rpl_flags: self.rpl_flags,
+            program_counter: self.program_counter,
+            index: self.index,
+            stack_pointer: self.stack_pointer,
+            stack: self.stack.clone(),
+            delay_timer: self.delay_timer.get(),
+            sound_timer: self.sound_timer.get(),
+            planes: self.planes,
+            audio_pattern: self.audio_pattern,
+            pitch: self.pitch,
+            halted: self.halted,
+            memory: self.memory.bytes().to_vec(),
+            display,
+            keypad,
+        }
+    }
+
+    /// Returns the machine to a snapshot taken while running the same ROM on
+    /// the same platform.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        if snapshot.rom_hash != self.rom_hash {
+            return Err(SnapshotError::WrongRom);
+        }
+        if snapshot.platform != self.platform {
+            return Err(SnapshotError::WrongPlatform);
+        }
+        let resolution = (snapshot.display.width(), snapshot.display.height());
+        let stack_fits = match self.quirks.stack {
+            Stack::Memory => snapshot.stack.is_empty(),
+            Stack::Dedicated(depth) => {
+                snapshot.stack.len() == depth && snapshot.stack_pointer <= depth
+            }
+        };
+        if snapshot.memory.len() != self.memory.bytes().len()
+            || !stack_fits
+            || (resolution != (LORES_WIDTH, LORES_HEIGHT)
+                && resolution != (HIRES_WIDTH, HIRES_HEIGHT))
+        {
+            return Err(SnapshotError::Corrupt);
+        }
+
+        self.gpr = snapshot.gpr;
+        self.rpl_flags = snapshot.rpl_flags;
+        self.program_counter = snapshot.program_counter;
+        self.index = snapshot.index;
+        self.stack_pointer = snapshot.stack_pointer;
+        self.stack.copy_from_slice(&snapshot.stack);
+        self.planes = snapshot.planes;
+        self.pitch = snapshot.pitch;
+        self.audio_pattern = snapshot.audio_pattern;
+        self.halted = snapshot.halted;
+        self.memory.restore(&snapshot.memory);
+
+        // An all-zero pattern means the program never loaded one, and the
+        // beeper should keep its default tone.
+        if self.audio_pattern != [0; PATTERN_SIZE] {
+            self.sound_timer.set_pattern(self.audio_pattern, self.pitch);
+        }
+        self.delay_timer.set(snapshot.delay_timer);
+        self.sound_timer.set(snapshot.sound_timer);
+
+        self.ui.set_resolution(resolution.0, resolution.1);
+        for (y, row) in snapshot.display.rows().enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                self.ui.write_pixel(x, y, *pixel);
+            }
+        }
+        for (key_code, pressed) in snapshot.keypad.iter().enumerate() {
+            self.ui.set_key_pressed(key_code, *pressed);
         }
+
+        Ok(())
+    }
+
+    pub fn memory(&self) -> &memory::Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut memory::Memory {
+        &mut self.memory
+    }
+
+    pub fn ui(&self) -> &T {
+        &self.ui
     }
 
-    fn fetch_instruction(&mut self) -> Opcode {
-        let instruction = Opcode(self.memory.read_u16_at(self.program_counter));
+    pub fn ui_mut(&mut self) -> &mut T {
+        &mut self.ui
+    }
+
+    fn fetch_instruction(&mut self) -> Result<Opcode, Fault> {
+        let instruction = Opcode(self.memory.read_u16_at(self.program_counter)?);
         self.program_counter += memory::WORD_SIZE;
-        instruction
+        Ok(instruction)
     }
 
-    fn push(&mut self, value: u16) {
+    fn push(&mut self, value: u16) -> Result<(), Fault> {
+        if let Stack::Dedicated(_) = self.quirks.stack {
+            let top = self
+                .stack
+                .get_mut(self.stack_pointer)
+                .ok_or(Fault::StackOverflow)?;
+            *top = value;
+            self.stack_pointer += 1;
+            return Ok(());
+        }
+
+        if self.stack_pointer <= memory::STACK_BASE - memory::STACK_DEPTH * memory::WORD_SIZE {
+            return Err(Fault::StackOverflow);
+        }
+
         self.stack_pointer -= memory::WORD_SIZE;
-        self.memory.write_u16_at(value, self.stack_pointer);
+        self.memory.write_u16_at(value, self.stack_pointer)?;
+        Ok(())
     }
 
-    fn pop(&mut self) -> u16 {
-        let value = self.memory.read_u16_at(self.stack_pointer);
+    fn pop(&mut self) -> Result<u16, Fault> {
+        if let Stack::Dedicated(_) = self.quirks.stack {
+            if self.stack_pointer == 0 {
+                return Err(Fault::StackUnderflow);
+            }
+            self.stack_pointer -= 1;
+            return Ok(self.stack[self.stack_pointer]);
+        }
+
+        if self.stack_pointer >= memory::STACK_BASE {
+            return Err(Fault::StackUnderflow);
+        }
+
+        let value = self.memory.read_u16_at(self.stack_pointer)?;
         self.stack_pointer += memory::WORD_SIZE;
-        value
+        Ok(value)
     }
 
-    fn call(&mut self, addr: u16) {
-        self.push(self.program_counter as u16);
+    fn call(&mut self, addr: u16) -> Result<(), Fault> {
+        self.push(self.program_counter as u16)?;
         self.program_counter = addr as usize;
+        Ok(())
+    }
+
+    fn ret(&mut self) -> Result<(), Fault> {
+        self.program_counter = self.pop()? as usize;
+        Ok(())
+    }
+
+    fn sys(&mut self, address: usize) -> Result<(), Fault> {
+        match self.sys_routines.get_mut(&address) {
+            Some(routine) => {
+                routine(&mut SysContext {
+                    gpr: &mut self.gpr,
+                    index: &mut self.index,
+                    memory: &mut self.memory,
+                    ui: &mut self.ui,
+                });
+                Ok(())
+            }
+            // `0000` is almost always zeroed memory the program ran into.
+            None if address == 0 => Err(Fault::IllegalOpcode),
+            None if self.sys_mode == SysMode::Fault => Err(Fault::MachineCodeCall),
+            None => Ok(()),
+        }
     }
 
-    fn ret(&mut self) {
-        self.program_counter = self.pop() as usize;
+    fn draw(&mut self, x: u8, y: u8, z: u8) -> Result<(), Fault> {
+        self.draw_rows(x, y, 1, z as usize)
     }
 
-    fn draw(&mut self, x: u8, y: u8, z: u8) {
-        assert!(
-            z <= 15,
-            "Sprite size is {} when the maximum allowed sprite size is 15",
-            z
-        );
+    /// Draws a SUPER-CHIP 16-line sprite, which is 16 pixels wide in high
+    /// resolution or on XO-CHIP, and 8 pixels wide otherwise.
+    fn draw_large(&mut self, x: u8, y: u8) -> Result<(), Fault> {
+        if self.platform.has_xochip() || self.ui.resolution() == (HIRES_WIDTH, HIRES_HEIGHT) {
+            self.draw_rows(x, y, 2, 16)
+        } else {
+            self.draw_rows(x, y, 1, 16)
+        }
+    }
 
+    fn draw_rows(&mut self, x: u8, y: u8, row_size: usize, rows: usize) -> Result<(), Fault> {
+        let sprite_size = row_size * rows * self.planes.count_ones() as usize;
         self.gpr[0xf] = display::draw_sprite(
             &mut self.ui,
             x as usize,
             y as usize,
-            &self.memory.0[self.index..self.index + z as usize],
+            self.memory.slice(self.index, sprite_size)?,
+            row_size,
+            self.planes,
+            self.quirks.clip_sprites,
         ) as u8;
+        Ok(())
     }
 
-    fn skip_if(&mut self, predicate: bool) {
+    fn skip_if(&mut self, predicate: bool) -> Result<(), Fault> {
         if predicate {
-            self.program_counter += memory::WORD_SIZE;
+            // XO-CHIP's "MOV I, long" is twice as long as any other
+            // instruction, so skipping over it takes two words.
+            if self.platform.has_xochip()
+                && self.memory.read_u16_at(self.program_counter)? == LONG_LOAD_OPCODE
+            {
+                self.program_counter += 2 * memory::WORD_SIZE;
+            } else {
+                self.program_counter += memory::WORD_SIZE;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the registers from Vx to Vy, in descending order when x > y.
+    fn register_range(x: usize, y: usize) -> Vec<usize> {
+        if x <= y {
+            (x..=y).collect()
+        } else {
+            (y..=x).rev().collect()
+        }
+    }
+
+    fn save_range(&mut self, x: usize, y: usize) -> Result<(), Fault> {
+        for (offset, reg) in Self::register_range(x, y).into_iter().enumerate() {
+            self.memory.write_at(&[self.gpr[reg]], self.index + offset)?;
         }
+        Ok(())
     }
 
-    fn bcd(&mut self, number: u8) {
+    fn load_range(&mut self, x: usize, y: usize) -> Result<(), Fault> {
+        for (offset, reg) in Self::register_range(x, y).into_iter().enumerate() {
+            self.memory
+                .read_at(&mut self.gpr[reg..=reg], self.index + offset)?;
+        }
+        Ok(())
+    }
+
+    fn bcd(&mut self, number: u8) -> Result<(), Fault> {
         let bcd = [
-            ((number / 100) % 10) as u8,
-            ((number / 10) % 10) as u8,
-            (number % 10) as u8,
+            (number / 100) % 10,
+            (number / 10) % 10,
+            number % 10,
         ];
-        self.memory.write_at(&bcd, self.index);
+        self.memory.write_at(&bcd, self.index)?;
+        Ok(())
     }
 
-    fn load_regs(&mut self, reg_count: usize) {
+    fn load_regs(&mut self, reg_count: usize) -> Result<(), Fault> {
         self.memory
-            .read_at(&mut self.gpr[0..reg_count + 1], self.index);
+            .read_at(&mut self.gpr[0..reg_count + 1], self.index)?;
+        if self.quirks.load_store_increments_index {
+            self.index += reg_count + 1;
+        }
+        Ok(())
     }
 
-    fn store_regs(&mut self, reg_count: usize) {
+    fn store_regs(&mut self, reg_count: usize) -> Result<(), Fault> {
         self.memory
-            .write_at(&self.gpr[0..reg_count + 1], self.index);
+            .write_at(&self.gpr[0..reg_count + 1], self.index)?;
+        if self.quirks.load_store_increments_index {
+            self.index += reg_count + 1;
+        }
+        Ok(())
     }
 
-    pub fn execute(&mut self) {
-        let opcode = self.fetch_instruction();
+    /// Returns the operand of `SHR Vx` and `SHL Vx`.
+    fn shift_operand(&self, x: usize, y: usize) -> u8 {
+        if self.quirks.shift_uses_vy {
+            self.gpr[y]
+        } else {
+            self.gpr[x]
+        }
+    }
+
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.gpr[0xf] = 0;
+        }
+    }
 
-        match opcode.to_nibble_tuple() {
-            opcode!("JMP addr") => {
-                self.program_counter = opcode.tribble() as usize;
+    pub fn execute(&mut self) -> Result<StepOutcome, CpuFault> {
+        if self.halted {
+            return Ok(StepOutcome::Halted);
+        }
+
+        let address = self.program_counter;
+        let opcode = self.fetch_instruction().map_err(|fault| fault.at(address, 0))?;
+        self.execute_opcode(&opcode)
+            .map_err(|fault| fault.at(address, opcode.0))
+    }
+
+    fn execute_opcode(&mut self, opcode: &Opcode) -> Result<StepOutcome, Fault> {
+        match Opcode::decode(opcode.0, self.platform) {
+            Instruction::Jump { addr } => {
+                self.program_counter = addr;
             }
 
-            opcode!("JMP V0, addr") => {
-                self.program_counter = (opcode.tribble() + self.gpr[0] as u16) as usize;
+            Instruction::JumpOffset { addr } => {
+                let offset_reg = if self.quirks.jump_uses_vx {
+                    addr >> 8
+                } else {
+                    0
+                };
+                self.program_counter = addr + self.gpr[offset_reg] as usize;
             }
 
-            opcode!("CALL addr") => {
-                self.call(opcode.tribble());
+            Instruction::Call { addr } => {
+                self.call(addr as u16)?;
             }
 
-            opcode!("RET") => {
-                self.ret();
+            Instruction::Return => {
+                self.ret()?;
             }
 
-            opcode!("MOV Vx, byte") => {
-                self.gpr[opcode.reg1()] = opcode.byte();
+            Instruction::SetByte { x, byte } => {
+                self.gpr[x] = byte;
             }
 
-            opcode!("MOV Vx, Vy") => {
-                self.gpr[opcode.reg1()] = self.gpr[opcode.reg2()];
+            Instruction::Set { x, y } => {
+                self.gpr[x] = self.gpr[y];
             }
 
-            opcode!("MOV I, addr") => {
-                self.index = opcode.tribble() as usize;
+            Instruction::SetIndex { addr } => {
+                self.index = addr;
             }
 
-            opcode!("MOV DT, Vx") => {
-                self.delay_timer.set(self.gpr[opcode.reg1()] as u64);
+            Instruction::SetDelay { x } => {
+                self.delay_timer.set(self.gpr[x]);
             }
 
-            opcode!("MOV Vx, DT") => {
-                self.gpr[opcode.reg1()] = self.delay_timer.get() as u8;
+            Instruction::GetDelay { x } => {
+                self.gpr[x] = self.delay_timer.get();
             }
 
-            opcode!("MOV ST, Vx") => {
-                self.sound_timer.set(self.gpr[opcode.reg1()] as u64);
+            Instruction::SetSound { x } => {
+                self.sound_timer.set(self.gpr[x]);
             }
 
-            opcode!("MOV Vx, K") => 'outer: loop {
-                    for key_code in 0..16 {
-                        if self.ui.is_key_pressed(key_code) {
-                            self.gpr[opcode.reg1()] = key_code as u8;
-                            break 'outer;
-                        }
+            Instruction::WaitKey { x } => {
+                // Rather than blocking, re-execute the instruction until a key
+                // is pressed, so the timers and the UI keep running meanwhile.
+                match (0..16).find(|key_code| self.ui.is_key_pressed(*key_code)) {
+                    Some(key_code) => self.gpr[x] = key_code as u8,
+                    None => {
+                        self.program_counter -= memory::WORD_SIZE;
+                        return Ok(StepOutcome::WaitingForKey);
                     }
-                    std::thread::sleep(std::time::Duration::from_millis(5));
-            },
+                }
+            }
 
-            opcode!("ADD Vx, byte") => {
-                self.gpr[opcode.reg1()] = self.gpr[opcode.reg1()].wrapping_add(opcode.byte());
+            Instruction::AddByte { x, byte } => {
+                self.gpr[x] = self.gpr[x].wrapping_add(byte);
             }
 
-            opcode!("ADD Vx, Vy") => {
-                self.gpr[opcode.reg1()] =
-                    match self.gpr[opcode.reg1()].checked_add(self.gpr[opcode.reg2()]) {
-                    Some(value) => { 
-                        self.gpr[0xf] = 0;
-                        value
+            Instruction::Add { x, y } => {
+                let (value, carry) = self.gpr[x].overflowing_add(self.gpr[y]);
+                self.gpr[0xf] = carry as u8;
+                self.gpr[x] = value;
             }
-                    None => {
-                        self.gpr[0xf] = 1;
-                        self.gpr[opcode.reg1()].wrapping_add(self.gpr[opcode.reg2()])
-                    }
-                }
+
+            Instruction::AddIndex { x } => {
+                self.index = self.index.wrapping_add(self.gpr[x] as usize);
             }
 
-            opcode!("ADD I, Vx") => {
-                self.index = self.index.wrapping_add(self.gpr[opcode.reg1()] as usize);
+            Instruction::Sub { x, y } => {
+                let (value, borrow) = self.gpr[x].overflowing_sub(self.gpr[y]);
+                self.gpr[0xf] = !borrow as u8;
+                self.gpr[x] = value;
             }
 
-            opcode!("SUB Vx, Vy") => {
-                self.gpr[opcode.reg1()] =
-                    match self.gpr[opcode.reg1()].checked_sub(self.gpr[opcode.reg2()]) {
-                    Some(value) => { 
-                        self.gpr[0xf] = 1;
-                        value
-                    }
-                    None => {
-                        self.gpr[0xf] = 0;
-                        self.gpr[opcode.reg1()].wrapping_sub(self.gpr[opcode.reg2()])
-                    }
-                }
+            Instruction::ReverseSub { x, y } => {
+                let (value, borrow) = self.gpr[y].overflowing_sub(self.gpr[x]);
+                self.gpr[0xf] = !borrow as u8;
+                self.gpr[x] = value;
             }
 
-            opcode!("RSUB Vx, Vy") => {
-                self.gpr[opcode.reg1()] =
-                    match self.gpr[opcode.reg2()].checked_sub(self.gpr[opcode.reg1()]) {
-                    Some(value) => { 
-                        self.gpr[0xf] = 1;
-                        value
-                    }
-                    None => {
-                        self.gpr[0xf] = 0;
-                        self.gpr[opcode.reg2()].wrapping_sub(self.gpr[opcode.reg1()])
-                    }
-                }
+            Instruction::Or { x, y } => {
+                self.gpr[x] |= self.gpr[y];
+                self.reset_vf_after_logic();
+            }
+
+            Instruction::And { x, y } => {
+                self.gpr[x] &= self.gpr[y];
+                self.reset_vf_after_logic();
+            }
+
+            Instruction::Xor { x, y } => {
+                self.gpr[x] ^= self.gpr[y];
+                self.reset_vf_after_logic();
+            }
+
+            Instruction::ShiftRight { x, y } => {
+                let value = self.shift_operand(x, y);
+                self.gpr[0xf] = value.get::<bitvec::LittleEndian>(0.into()) as u8;
+                self.gpr[x] = value >> 1;
+            }
+
+            Instruction::ShiftLeft { x, y } => {
+                let value = self.shift_operand(x, y);
+                self.gpr[0xf] = value.get::<bitvec::LittleEndian>(7.into()) as u8;
+                self.gpr[x] = value << 1;
+            }
+
+            Instruction::Random { x, byte } => {
+                self.gpr[x] = self.random.next_byte() & byte;
+            }
+
+            Instruction::SkipIfEqualByte { x, byte } => {
+                self.skip_if(self.gpr[x] == byte)?;
+            }
+
+            Instruction::SkipIfEqual { x, y } => {
+                self.skip_if(self.gpr[x] == self.gpr[y])?;
+            }
+
+            Instruction::SkipIfNotEqualByte { x, byte } => {
+                self.skip_if(self.gpr[x] != byte)?;
             }
 
-            opcode!("OR Vx, Vy") => {
-                self.gpr[opcode.reg1()] |= self.gpr[opcode.reg2()];
+            Instruction::SkipIfNotEqual { x, y } => {
+                self.skip_if(self.gpr[x] != self.gpr[y])?;
             }
 
-            opcode!("AND Vx, Vy") => {
-                self.gpr[opcode.reg1()] &= self.gpr[opcode.reg2()];
+            Instruction::SkipIfKey { x } => {
+                self.skip_if(self.ui.is_key_pressed((self.gpr[x] & 0xf) as usize))?;
             }
 
-            opcode!("XOR Vx, Vy") => {
-                self.gpr[opcode.reg1()] ^= self.gpr[opcode.reg2()];
+            Instruction::SkipIfNotKey { x } => {
+                self.skip_if(!self.ui.is_key_pressed((self.gpr[x] & 0xf) as usize))?;
             }
 
-            opcode!("SHR Vx") => {
-                self.gpr[0xf] = self.gpr[opcode.reg1()].get::<bitvec::LittleEndian>(0.into()) as u8;
-                self.gpr[opcode.reg1()] >>= 1;
+            Instruction::Clear => {
+                self.ui.clear_display(self.planes);
             }
 
-            opcode!("SHL Vx") => {
-                self.gpr[0xf] = self.gpr[opcode.reg1()].get::<bitvec::LittleEndian>(7.into()) as u8;
-                self.gpr[opcode.reg1()] <<= 1;
+            Instruction::ScrollDown { n } => {
+                self.ui.scroll_display(0, n as isize, self.planes);
             }
 
-            opcode!("RND Vx, tribble") => {
-                self.gpr[opcode.reg1()] = self.rng.gen_range(0, opcode.byte() as u16 + 1) as u8;
+            Instruction::ScrollUp { n } => {
+                self.ui.scroll_display(0, -(n as isize), self.planes);
             }
 
-            opcode!("SKE Vx, byte") => {
-                self.skip_if(self.gpr[opcode.reg1()] == opcode.byte());
+            Instruction::ScrollRight => {
+                self.ui.scroll_display(4, 0, self.planes);
             }
 
-            opcode!("SKE Vx, Vy") => {
-                self.skip_if(self.gpr[opcode.reg1()] == self.gpr[opcode.reg2()]);
+            Instruction::ScrollLeft => {
+                self.ui.scroll_display(-4, 0, self.planes);
             }
 
-            opcode!("SKNE Vx, byte") => {
-                self.skip_if(self.gpr[opcode.reg1()] != opcode.byte());
+            Instruction::LowRes => {
+                self.ui.set_resolution(LORES_WIDTH, LORES_HEIGHT);
             }
 
-            opcode!("SKNE Vx, Vy") => {
-                self.skip_if(self.gpr[opcode.reg1()] != self.gpr[opcode.reg2()]);
+            Instruction::HighRes => {
+                self.ui.set_resolution(HIRES_WIDTH, HIRES_HEIGHT);
             }
 
-            opcode!("SKP Vx") => {
-                self.skip_if(self.ui.is_key_pressed(self.gpr[opcode.reg1()] as usize));
+            Instruction::Exit => {
+                self.halted = true;
+                return Ok(StepOutcome::Halted);
             }
 
-            opcode!("SKNP Vx") => {
-                self.skip_if(!self.ui.is_key_pressed(self.gpr[opcode.reg1()] as usize));
+            Instruction::Draw { x, y, n: 0 } if self.platform.has_superchip() => {
+                self.draw_large(self.gpr[x], self.gpr[y])?;
             }
 
-            opcode!("CLS") => {
-                self.ui.clear_display();
+            Instruction::Draw { x, y, n } => {
+                self.draw(self.gpr[x], self.gpr[y], n)?;
             }
 
-            opcode!("DRW Vx, Vy, nibble") => {
-                self.draw(
-                    self.gpr[opcode.reg1()],
-                    self.gpr[opcode.reg2()],
-                    opcode.nibble(),
-                );
+            Instruction::Bcd { x } => {
+                self.bcd(self.gpr[x])?;
             }
 
-            opcode!("BCD Vx") => {
-                self.bcd(self.gpr[opcode.reg1()]);
+            Instruction::Load { x } => {
+                self.load_regs(x)?;
             }
 
-            opcode!("LD Vx, [I]") => {
-                self.load_regs(opcode.reg1());
+            Instruction::Store { x } => {
+                self.store_regs(x)?;
             }
 
-            opcode!("STR [I], Vx") => {
-                self.store_regs(opcode.reg1());
+            Instruction::Font { x } => {
+                self.index = (self.gpr[x] & 0xf) as usize * 5;
             }
 
-            opcode!("FONT Vx") => {
-                self.index = (self.gpr[opcode.reg1()] * 5) as usize;
+            Instruction::BigFont { x } => {
+                self.index =
+                    memory::BIG_FONTS_BASE + (self.gpr[x] & 0xf) as usize * memory::BIG_FONT_SIZE;
             }
-            _ => {
-                println!(
-                    "Unsupported opcode: 0x{:X} at 0x{:X}",
-                    opcode.0,
-                    self.program_counter - 2
-                );
-                std::process::exit(1);
+
+            Instruction::StoreFlags { x } => {
+                self.rpl_flags[0..=x].copy_from_slice(&self.gpr[0..=x]);
+            }
+
+            Instruction::LoadFlags { x } => {
+                self.gpr[0..=x].copy_from_slice(&self.rpl_flags[0..=x]);
+            }
+
+            Instruction::SaveRange { x, y } => {
+                self.save_range(x, y)?;
+            }
+
+            Instruction::LoadRange { x, y } => {
+                self.load_range(x, y)?;
+            }
+
+            Instruction::SetIndexLong => {
+                self.index = self.memory.read_u16_at(self.program_counter)? as usize;
+                self.program_counter += memory::WORD_SIZE;
+            }
+
+            Instruction::Plane { planes } => {
+                self.planes = planes & 0b11;
+            }
+
+            Instruction::Audio => {
+                self.memory.read_at(&mut self.audio_pattern, self.index)?;
+                self.sound_timer.set_pattern(self.audio_pattern, self.pitch);
+            }
+
+            Instruction::Pitch { x } => {
+                self.pitch = self.gpr[x];
+                self.sound_timer.set_pattern(self.audio_pattern, self.pitch);
             }
+
+            Instruction::Sys { addr } => {
+                self.sys(addr)?;
+            }
+
+            Instruction::Illegal { .. } => return Err(Fault::IllegalOpcode),
         }
+
+        Ok(StepOutcome::Executed)
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -1,12 +1,25 @@
+use super::Platform;
+use std::fmt;
+
 #[macro_export]
 macro_rules! opcode {
     ("CLS")                 =>    ((0x0, 0x0, 0xE, 0));
     ("RET")                 =>    ((0x0, 0x0, 0xE, 0xE));
+    ("SCD nibble")          =>    ((0x0, 0x0, 0xC, _));
+    ("SCU nibble")          =>    ((0x0, 0x0, 0xD, _));
+    ("SCR")                 =>    ((0x0, 0x0, 0xF, 0xB));
+    ("SCL")                 =>    ((0x0, 0x0, 0xF, 0xC));
+    ("EXIT")                =>    ((0x0, 0x0, 0xF, 0xD));
+    ("LOW")                 =>    ((0x0, 0x0, 0xF, 0xE));
+    ("HIGH")                =>    ((0x0, 0x0, 0xF, 0xF));
+    ("SYS addr")            =>    ((0x0, _, _, _));
     ("JMP addr")            =>    ((0x1, _, _, _));
     ("CALL addr")           =>    ((0x2, _, _, _));
     ("SKE Vx, byte")        =>    ((0x3, _, _, _));
     ("SKNE Vx, byte")       =>    ((0x4, _, _, _));
     ("SKE Vx, Vy")          =>    ((0x5, _, _, 0x0));
+    ("SAVE Vx, Vy")         =>    ((0x5, _, _, 0x2));
+    ("LOAD Vx, Vy")         =>    ((0x5, _, _, 0x3));
     ("MOV Vx, byte")        =>    ((0x6, _, _, _));
     ("ADD Vx, byte")        =>    ((0x7, _, _, _));
     ("MOV Vx, Vy")          =>    ((0x8, _, _, 0x0));
@@ -25,15 +38,22 @@ macro_rules! opcode {
     ("DRW Vx, Vy, nibble")  =>    ((0xD, _, _, _));
     ("SKP Vx")              =>    ((0xE, _, 0x9, 0xE));
     ("SKNP Vx")             =>    ((0xE, _, 0xA, 0x1));
+    ("MOV I, long")         =>    ((0xF, 0x0, 0x0, 0x0));
+    ("PLANE n")             =>    ((0xF, _, 0x0, 0x1));
+    ("AUDIO")               =>    ((0xF, 0x0, 0x0, 0x2));
     ("MOV Vx, DT")          =>    ((0xF, _, 0x0, 0x7));
     ("MOV Vx, K")           =>    ((0xF, _, 0x0, 0xA));
     ("MOV DT, Vx")          =>    ((0xF, _, 0x1, 0x5));
     ("MOV ST, Vx")          =>    ((0xF, _, 0x1, 0x8));
     ("ADD I, Vx")           =>    ((0xF, _, 0x1, 0xE));
     ("FONT Vx")             =>    ((0xF, _, 0x2, 0x9));
+    ("HFONT Vx")            =>    ((0xF, _, 0x3, 0x0));
     ("BCD Vx")              =>    ((0xF, _, 0x3, 0x3));
+    ("PITCH Vx")            =>    ((0xF, _, 0x3, 0xA));
     ("STR [I], Vx")         =>    ((0xF, _, 0x5, 0x5));
     ("LD Vx, [I]")          =>    ((0xF, _, 0x6, 0x5));
+    ("STR R, Vx")           =>    ((0xF, _, 0x7, 0x5));
+    ("LD Vx, R")            =>    ((0xF, _, 0x8, 0x5));
 }
 
 fn bit_slice(number: u16, offset: u8, size: u8) -> u16 {
@@ -59,7 +79,7 @@ impl Opcode {
     }
 
     pub fn nibble(&self) -> u8 {
-        get_nibble(self.0, 0) as u8
+        get_nibble(self.0, 0)
     }
 
     pub fn tribble(&self) -> u16 {
@@ -74,4 +94,309 @@ impl Opcode {
             get_nibble(self.0, 0),
         )
     }
+
+    /// Decodes an instruction word as `platform` reads it. The words starting
+    /// with 0 that it has no instruction for are calls to machine code, and
+    /// other words it has no instruction for are illegal. `MOV I, long` takes
+    /// its address from the word after it, which the caller reads.
+    pub fn decode(opcode: u16, platform: Platform) -> Instruction {
+        let opcode = Opcode(opcode);
+        let (superchip, xochip) = (platform.has_superchip(), platform.has_xochip());
+        let (x, y, n, byte, addr) = (
+            opcode.reg1(),
+            opcode.reg2(),
+            opcode.nibble(),
+            opcode.byte(),
+            opcode.tribble() as usize,
+        );
+
+        match opcode.to_nibble_tuple() {
+            opcode!("CLS") => Instruction::Clear,
+            opcode!("RET") => Instruction::Return,
+            opcode!("SCD nibble") if superchip => Instruction::ScrollDown { n },
+            opcode!("SCU nibble") if xochip => Instruction::ScrollUp { n },
+            opcode!("SCR") if superchip => Instruction::ScrollRight,
+            opcode!("SCL") if superchip => Instruction::ScrollLeft,
+            opcode!("EXIT") if superchip => Instruction::Exit,
+            opcode!("LOW") if superchip => Instruction::LowRes,
+            opcode!("HIGH") if superchip => Instruction::HighRes,
+            opcode!("SYS addr") => Instruction::Sys { addr },
+            opcode!("JMP addr") => Instruction::Jump { addr },
+            opcode!("CALL addr") => Instruction::Call { addr },
+            opcode!("SKE Vx, byte") => Instruction::SkipIfEqualByte { x, byte },
+            opcode!("SKNE Vx, byte") => Instruction::SkipIfNotEqualByte { x, byte },
+            opcode!("SKE Vx, Vy") => Instruction::SkipIfEqual { x, y },
+            opcode!("SAVE Vx, Vy") if xochip => Instruction::SaveRange { x, y },
+            opcode!("LOAD Vx, Vy") if xochip => Instruction::LoadRange { x, y },
+            opcode!("MOV Vx, byte") => Instruction::SetByte { x, byte },
+            opcode!("ADD Vx, byte") => Instruction::AddByte { x, byte },
+            opcode!("MOV Vx, Vy") => Instruction::Set { x, y },
+            opcode!("OR Vx, Vy") => Instruction::Or { x, y },
+            opcode!("AND Vx, Vy") => Instruction::And { x, y },
+            opcode!("XOR Vx, Vy") => Instruction::Xor { x, y },
+            opcode!("ADD Vx, Vy") => Instruction::Add { x, y },
+            opcode!("SUB Vx, Vy") => Instruction::Sub { x, y },
+            opcode!("SHR Vx") => Instruction::ShiftRight { x, y },
+            opcode!("RSUB Vx, Vy") => Instruction::ReverseSub { x, y },
+            opcode!("SHL Vx") => Instruction::ShiftLeft { x, y },
+            opcode!("SKNE Vx, Vy") => Instruction::SkipIfNotEqual { x, y },
+            opcode!("MOV I, addr") => Instruction::SetIndex { addr },
+            opcode!("JMP V0, addr") => Instruction::JumpOffset { addr },
+            opcode!("RND Vx, tribble") => Instruction::Random { x, byte },
+            opcode!("DRW Vx, Vy, nibble") => Instruction::Draw { x, y, n },
+            opcode!("SKP Vx") => Instruction::SkipIfKey { x },
+            opcode!("SKNP Vx") => Instruction::SkipIfNotKey { x },
+            opcode!("MOV I, long") if xochip => Instruction::SetIndexLong,
+            opcode!("PLANE n") if xochip => Instruction::Plane { planes: x as u8 },
+            opcode!("AUDIO") if xochip => Instruction::Audio,
+            opcode!("MOV Vx, DT") => Instruction::GetDelay { x },
+            opcode!("MOV Vx, K") => Instruction::WaitKey { x },
+            opcode!("MOV DT, Vx") => Instruction::SetDelay { x },
+            opcode!("MOV ST, Vx") => Instruction::SetSound { x },
+            opcode!("ADD I, Vx") => Instruction::AddIndex { x },
+            opcode!("FONT Vx") => Instruction::Font { x },
+            opcode!("HFONT Vx") if superchip => Instruction::BigFont { x },
+            opcode!("BCD Vx") => Instruction::Bcd { x },
+            opcode!("PITCH Vx") if xochip => Instruction::Pitch { x },
+            opcode!("STR [I], Vx") => Instruction::Store { x },
+            opcode!("LD Vx, [I]") => Instruction::Load { x },
+            opcode!("STR R, Vx") if superchip => Instruction::StoreFlags { x },
+            opcode!("LD Vx, R") if superchip => Instruction::LoadFlags { x },
+            _ => Instruction::Illegal { opcode: opcode.0 },
+        }
+    }
+}
+
+/// A decoded instruction, named after what it does, with its operands. `x`
+/// and `y` are register numbers. See `opcode!` for the assembly syntax of
+/// each, which `Display` writes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[rustfmt::skip]
+pub enum Instruction {
+    Clear,
+    Return,
+    ScrollDown { n: u8 },
+    ScrollUp { n: u8 },
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    LowRes,
+    HighRes,
+    Sys { addr: usize },
+    Jump { addr: usize },
+    Call { addr: usize },
+    SkipIfEqualByte { x: usize, byte: u8 },
+    SkipIfNotEqualByte { x: usize, byte: u8 },
+    SkipIfEqual { x: usize, y: usize },
+    SaveRange { x: usize, y: usize },
+    LoadRange { x: usize, y: usize },
+    SetByte { x: usize, byte: u8 },
+    AddByte { x: usize, byte: u8 },
+    Set { x: usize, y: usize },
+    Or { x: usize, y: usize },
+    And { x: usize, y: usize },
+    Xor { x: usize, y: usize },
+    Add { x: usize, y: usize },
+    Sub { x: usize, y: usize },
+    /// Vy is only read under the shift quirk.
+    ShiftRight { x: usize, y: usize },
+    ReverseSub { x: usize, y: usize },
+    ShiftLeft { x: usize, y: usize },
+    SkipIfNotEqual { x: usize, y: usize },
+    SetIndex { addr: usize },
+    /// Jumps to `addr` plus V0, or plus Vx under the jump quirk, x being the
+    /// top nibble of `addr`.
+    JumpOffset { addr: usize },
+    Random { x: usize, byte: u8 },
+    /// With `n` 0, draws a SUPER-CHIP 16-line sprite.
+    Draw { x: usize, y: usize, n: u8 },
+    SkipIfKey { x: usize },
+    SkipIfNotKey { x: usize },
+    /// XO-CHIP's `MOV I, long`, whose address is the next word.
+    SetIndexLong,
+    Plane { planes: u8 },
+    Audio,
+    GetDelay { x: usize },
+    WaitKey { x: usize },
+    SetDelay { x: usize },
+    SetSound { x: usize },
+    AddIndex { x: usize },
+    Font { x: usize },
+    BigFont { x: usize },
+    Bcd { x: usize },
+    Pitch { x: usize },
+    Store { x: usize },
+    Load { x: usize },
+    StoreFlags { x: usize },
+    LoadFlags { x: usize },
+    /// A word that is no instruction on the platform it was decoded for,
+    /// including the instructions only later platforms have.
+    Illegal { opcode: u16 },
+}
+
+impl Instruction {
+    /// Returns whether the instruction skips the next one on a condition.
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            self,
+            Instruction::SkipIfEqualByte { .. }
+                | Instruction::SkipIfNotEqualByte { .. }
+                | Instruction::SkipIfEqual { .. }
+                | Instruction::SkipIfNotEqual { .. }
+                | Instruction::SkipIfKey { .. }
+                | Instruction::SkipIfNotKey { .. }
+        )
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Clear => write!(f, "CLS"),
+            Instruction::Return => write!(f, "RET"),
+            Instruction::ScrollDown { n } => write!(f, "SCD {}", n),
+            Instruction::ScrollUp { n } => write!(f, "SCU {}", n),
+            Instruction::ScrollRight => write!(f, "SCR"),
+            Instruction::ScrollLeft => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::LowRes => write!(f, "LOW"),
+            Instruction::HighRes => write!(f, "HIGH"),
+            Instruction::Sys { addr } => write!(f, "SYS {:#05x}", addr),
+            Instruction::Jump { addr } => write!(f, "JMP {:#05x}", addr),
+            Instruction::Call { addr } => write!(f, "CALL {:#05x}", addr),
+            Instruction::SkipIfEqualByte { x, byte } => write!(f, "SKE V{:X}, {:#04x}", x, byte),
+            Instruction::SkipIfNotEqualByte { x, byte } => {
+                write!(f, "SKNE V{:X}, {:#04x}", x, byte)
+            }
+            Instruction::SkipIfEqual { x, y } => write!(f, "SKE V{:X}, V{:X}", x, y),
+            Instruction::SaveRange { x, y } => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Instruction::LoadRange { x, y } => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            Instruction::SetByte { x, byte } => write!(f, "MOV V{:X}, {:#04x}", x, byte),
+            Instruction::AddByte { x, byte } => write!(f, "ADD V{:X}, {:#04x}", x, byte),
+            Instruction::Set { x, y } => write!(f, "MOV V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::Add { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            // Vy is kept when set, so the text assembles to the same word.
+            Instruction::ShiftRight { x, y: 0 } => write!(f, "SHR V{:X}", x),
+            Instruction::ShiftRight { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::ReverseSub { x, y } => write!(f, "RSUB V{:X}, V{:X}", x, y),
+            Instruction::ShiftLeft { x, y: 0 } => write!(f, "SHL V{:X}", x),
+            Instruction::ShiftLeft { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipIfNotEqual { x, y } => write!(f, "SKNE V{:X}, V{:X}", x, y),
+            Instruction::SetIndex { addr } => write!(f, "MOV I, {:#05x}", addr),
+            Instruction::JumpOffset { addr } => write!(f, "JMP V0, {:#05x}", addr),
+            Instruction::Random { x, byte } => write!(f, "RND V{:X}, {:#04x}", x, byte),
+            Instruction::Draw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipIfKey { x } => write!(f, "SKP V{:X}", x),
+            Instruction::SkipIfNotKey { x } => write!(f, "SKNP V{:X}", x),
+            Instruction::SetIndexLong => write!(f, "MOV I, long"),
+            Instruction::Plane { planes } => write!(f, "PLANE {}", planes),
+            Instruction::Audio => write!(f, "AUDIO"),
+            Instruction::GetDelay { x } => write!(f, "MOV V{:X}, DT", x),
+            Instruction::WaitKey { x } => write!(f, "MOV V{:X}, K", x),
+            Instruction::SetDelay { x } => write!(f, "MOV DT, V{:X}", x),
+            Instruction::SetSound { x } => write!(f, "MOV ST, V{:X}", x),
+            Instruction::AddIndex { x } => write!(f, "ADD I, V{:X}", x),
+            Instruction::Font { x } => write!(f, "FONT V{:X}", x),
+            Instruction::BigFont { x } => write!(f, "HFONT V{:X}", x),
+            Instruction::Bcd { x } => write!(f, "BCD V{:X}", x),
+            Instruction::Pitch { x } => write!(f, "PITCH V{:X}", x),
+            Instruction::Store { x } => write!(f, "STR [I], V{:X}", x),
+            Instruction::Load { x } => write!(f, "LD V{:X}, [I]", x),
+            Instruction::StoreFlags { x } => write!(f, "STR R, V{:X}", x),
+            Instruction::LoadFlags { x } => write!(f, "LD V{:X}, R", x),
+            Instruction::Illegal { opcode } => write!(f, "dw {:#06x}", opcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLATFORMS: [Platform; 3] = [Platform::Chip8, Platform::SuperChip, Platform::XoChip];
+
+    fn decode_all(opcode: u16) -> Vec<Instruction> {
+        PLATFORMS
+            .iter()
+            .map(|platform| Opcode::decode(opcode, *platform))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_the_operands() {
+        for platform in &PLATFORMS {
+            assert_eq!(
+                Opcode::decode(0xd12f, *platform),
+                Instruction::Draw { x: 1, y: 2, n: 0xf }
+            );
+            assert_eq!(
+                Opcode::decode(0x8ab6, *platform),
+                Instruction::ShiftRight { x: 0xa, y: 0xb }
+            );
+            assert_eq!(
+                Opcode::decode(0xc37f, *platform),
+                Instruction::Random { x: 3, byte: 0x7f }
+            );
+            assert_eq!(
+                Opcode::decode(0xb123, *platform),
+                Instruction::JumpOffset { addr: 0x123 }
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_each_instruction_only_on_the_platforms_that_have_it() {
+        use Instruction::*;
+        let illegal = |opcode| Illegal { opcode };
+        #[rustfmt::skip]
+        let cases = [
+            (0x00e0, [Clear, Clear, Clear]),
+            (0x00c3, [Sys { addr: 0x0c3 }, ScrollDown { n: 3 }, ScrollDown { n: 3 }]),
+            (0x00d3, [Sys { addr: 0x0d3 }, Sys { addr: 0x0d3 }, ScrollUp { n: 3 }]),
+            (0x00fd, [Sys { addr: 0x0fd }, Exit, Exit]),
+            (0x5122, [illegal(0x5122), illegal(0x5122), SaveRange { x: 1, y: 2 }]),
+            (0x5123, [illegal(0x5123), illegal(0x5123), LoadRange { x: 1, y: 2 }]),
+            (0xf000, [illegal(0xf000), illegal(0xf000), SetIndexLong]),
+            (0xf201, [illegal(0xf201), illegal(0xf201), Plane { planes: 2 }]),
+            (0xf130, [illegal(0xf130), BigFont { x: 1 }, BigFont { x: 1 }]),
+            (0xf475, [illegal(0xf475), StoreFlags { x: 4 }, StoreFlags { x: 4 }]),
+            (0x8008, [illegal(0x8008), illegal(0x8008), illegal(0x8008)]),
+        ];
+        for (opcode, expected) in cases.iter() {
+            assert_eq!(decode_all(*opcode), expected, "{:04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn superchip_opcodes_are_sys_calls_on_chip8() {
+        for opcode in &[0x00c1, 0x00fb, 0x00fc, 0x00fd, 0x00fe, 0x00ff] {
+            let sys = Instruction::Sys {
+                addr: usize::from(*opcode),
+            };
+            assert_eq!(Opcode::decode(*opcode, Platform::Chip8), sys);
+            assert_ne!(Opcode::decode(*opcode, Platform::SuperChip), sys);
+        }
+    }
+
+    #[test]
+    fn displays_as_assembly() {
+        let text = |opcode, platform| Opcode::decode(opcode, platform).to_string();
+
+        assert_eq!(text(0x00fb, Platform::Chip8), "SYS 0x0fb");
+        assert_eq!(text(0x00fb, Platform::SuperChip), "SCR");
+        assert_eq!(text(0x00d4, Platform::XoChip), "SCU 4");
+        assert_eq!(text(0x3a07, Platform::Chip8), "SKE VA, 0x07");
+        assert_eq!(text(0x8ab6, Platform::Chip8), "SHR VA, VB");
+        assert_eq!(text(0x8a06, Platform::Chip8), "SHR VA");
+        assert_eq!(text(0xd12f, Platform::Chip8), "DRW V1, V2, 15");
+        assert_eq!(text(0xf565, Platform::SuperChip), "LD V5, [I]");
+        assert_eq!(text(0xf385, Platform::SuperChip), "LD V3, R");
+        assert_eq!(text(0xf385, Platform::Chip8), "dw 0xf385");
+        assert_eq!(text(0x5122, Platform::XoChip), "SAVE V1, V2");
+        assert_eq!(text(0xf201, Platform::XoChip), "PLANE 2");
+    }
 }
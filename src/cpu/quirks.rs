@@ -0,0 +1,118 @@
+use super::Platform;
+
+/// Behaviours that differ between CHIP-8 interpreters. ROMs written for one
+/// interpreter often rely on its particular interpretation.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Quirks {
+    /// `SHR Vx` and `SHL Vx` shift Vy into Vx, rather than shifting Vx in place.
+    pub shift_uses_vy: bool,
+    /// `LD Vx, [I]` and `STR [I], Vx` leave I pointing past the last register
+    /// accessed, rather than leaving it unchanged.
+    pub load_store_increments_index: bool,
+    /// `JMP V0, addr` is BXNN: it jumps to XNN plus Vx, rather than to NNN
+    /// plus V0.
+    pub jump_uses_vx: bool,
+    /// `OR`, `AND` and `XOR` reset VF to 0.
+    pub logic_resets_vf: bool,
+    /// Sprites are clipped at the edges of the display, rather than wrapping
+    /// around to the opposite edge.
+    pub clip_sprites: bool,
+    /// Where `CALL` keeps return addresses. Every preset keeps them in memory,
+    /// where programs that nest calls deeply or leave subroutines without
+    /// returning keep running; a dedicated stack has to be asked for.
+    pub stack: Stack,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Stack {
+    /// In memory, growing down from `memory::STACK_BASE`, where programs can
+    /// read and overwrite them. Holds `memory::STACK_DEPTH` return addresses.
+    Memory,
+    /// In a separate stack of the given depth, out of the program's reach.
+    Dedicated(usize),
+}
+
+impl Stack {
+    /// Returns the number that identifies the stack in movies: its depth, or
+    /// 0 for the stack in memory.
+    pub fn id(self) -> u16 {
+        match self {
+            Stack::Memory => 0,
+            Stack::Dedicated(depth) => depth as u16,
+        }
+    }
+
+    pub fn from_id(id: u16) -> Stack {
+        match id {
+            0 => Stack::Memory,
+            depth => Stack::Dedicated(depth.into()),
+        }
+    }
+}
+
+pub const PRESET_NAMES: [&str; 5] = ["vip", "chip48", "schip", "xochip", "modern"];
+
+impl Quirks {
+    /// The original COSMAC VIP interpreter.
+    pub const VIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_index: true,
+        jump_uses_vx: false,
+        logic_resets_vf: true,
+        clip_sprites: true,
+        stack: Stack::Memory,
+    };
+
+    /// CHIP-48 on the HP-48 calculators.
+    pub const CHIP48: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_index: false,
+        jump_uses_vx: true,
+        logic_resets_vf: false,
+        clip_sprites: true,
+        stack: Stack::Memory,
+    };
+
+    /// SUPER-CHIP 1.1, which inherits the CHIP-48 behaviour.
+    pub const SCHIP: Quirks = Quirks::CHIP48;
+
+    /// XO-CHIP as implemented by Octo.
+    pub const XOCHIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_index: true,
+        jump_uses_vx: false,
+        logic_resets_vf: false,
+        clip_sprites: false,
+        stack: Stack::Memory,
+    };
+
+    /// The behaviour most modern interpreters share.
+    pub const MODERN: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_index: false,
+        jump_uses_vx: false,
+        logic_resets_vf: false,
+        clip_sprites: false,
+        stack: Stack::Memory,
+    };
+
+    pub fn from_preset(name: &str) -> Option<Quirks> {
+        match name {
+            "vip" => Some(Quirks::VIP),
+            "chip48" => Some(Quirks::CHIP48),
+            "schip" => Some(Quirks::SCHIP),
+            "xochip" => Some(Quirks::XOCHIP),
+            "modern" => Some(Quirks::MODERN),
+            _ => None,
+        }
+    }
+
+    /// Returns the quirks ROMs for `platform` most commonly expect.
+    pub fn for_platform(platform: Platform) -> Quirks {
+        match platform {
+            Platform::Chip8 => Quirks::MODERN,
+            Platform::SuperChip => Quirks::SCHIP,
+            Platform::XoChip => Quirks::XOCHIP,
+        }
+    }
+}
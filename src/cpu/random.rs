@@ -0,0 +1,120 @@
+use rand::{RngCore, SeedableRng};
+use rand_pcg::Pcg32;
+use std::io::{self, Read, Write};
+
+/// Where `RND` gets its random bytes.
+pub trait RandomSource {
+    fn next_byte(&mut self) -> u8;
+}
+
+/// A pseudorandom generator that produces the same bytes for the same seed,
+/// so that runs can be reproduced.
+pub struct SeededRandom(Pcg32);
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> SeededRandom {
+        SeededRandom(Pcg32::seed_from_u64(seed))
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn next_byte(&mut self) -> u8 {
+        (self.0.next_u32() >> 24) as u8
+    }
+}
+
+/// Passes on the bytes of another source, writing each one to a log that
+/// `ReplayRandom` can play back.
+pub struct RecordingRandom {
+    source: Box<dyn RandomSource>,
+    writer: Box<dyn Write>,
+}
+
+impl RecordingRandom {
+    pub fn new(source: Box<dyn RandomSource>, writer: Box<dyn Write>) -> RecordingRandom {
+        RecordingRandom { source, writer }
+    }
+}
+
+impl RandomSource for RecordingRandom {
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.source.next_byte();
+        if let Err(error) = self.writer.write_all(&[byte]) {
+            println!("Failed to write random byte: {}", error);
+        }
+        byte
+    }
+}
+
+/// Plays back a log written by `RecordingRandom`. Past the end of the log, it
+/// produces zeros.
+pub struct ReplayRandom {
+    log: Vec<u8>,
+    position: usize,
+}
+
+impl ReplayRandom {
+    pub fn read_from(reader: &mut dyn Read) -> io::Result<ReplayRandom> {
+        let mut log = Vec::new();
+        reader.read_to_end(&mut log)?;
+        Ok(ReplayRandom { log, position: 0 })
+    }
+}
+
+impl RandomSource for ReplayRandom {
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.log.get(self.position).cloned().unwrap_or(0);
+        self.position += 1;
+        byte
+    }
+}
+
+/// Produces the same bytes over and over, for tests that need predictable
+/// `RND` results.
+pub struct FixedRandom {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl FixedRandom {
+    pub fn new(bytes: &[u8]) -> FixedRandom {
+        assert!(!bytes.is_empty(), "FixedRandom needs at least one byte");
+        FixedRandom {
+            bytes: bytes.to_vec(),
+            position: 0,
+        }
+    }
+}
+
+impl RandomSource for FixedRandom {
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.bytes[self.position];
+        self.position = (self.position + 1) % self.bytes.len();
+        byte
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(source: &mut dyn RandomSource, count: usize) -> Vec<u8> {
+        (0..count).map(|_| source.next_byte()).collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_bytes() {
+        let first = bytes(&mut SeededRandom::new(42), 64);
+
+        assert_eq!(bytes(&mut SeededRandom::new(42), 64), first);
+        assert_ne!(bytes(&mut SeededRandom::new(43), 64), first);
+    }
+
+    #[test]
+    fn fixed_bytes_repeat() {
+        assert_eq!(
+            bytes(&mut FixedRandom::new(&[1, 2, 3]), 7),
+            [1, 2, 3, 1, 2, 3, 1]
+        );
+    }
+}
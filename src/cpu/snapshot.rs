@@ -0,0 +1,214 @@
+use super::audio::PATTERN_SIZE;
+use super::memory::XO_MEMORY_SIZE;
+use super::user_interface::{KeyPad, Screen, HIRES_HEIGHT, HIRES_WIDTH};
+use super::Platform;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"C8SS";
+
+/// The version of the snapshot format. Bump it whenever the format changes.
+const VERSION: u16 = 1;
+
+pub type RomHash = [u8; 20];
+
+/// Returns the SHA-1 hash of a ROM, which identifies the game a snapshot
+/// belongs to.
+pub fn hash_rom(rom: &[u8]) -> RomHash {
+    sha1::Sha1::from(rom).digest().bytes()
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    NotASnapshot,
+    UnsupportedVersion(u16),
+    /// The snapshot was taken while running a different ROM.
+    WrongRom,
+    /// The snapshot was taken on a different platform.
+    WrongPlatform,
+    /// The snapshot is truncated, or holds a value that is impossible for the
+    /// machine it was taken on.
+    Corrupt,
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> SnapshotError {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => SnapshotError::Corrupt,
+            _ => SnapshotError::Io(error),
+        }
+    }
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(error) => write!(f, "{}", error),
+            SnapshotError::NotASnapshot => write!(f, "Not a save state"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported save state version {}", version)
+            }
+            SnapshotError::WrongRom => write!(f, "The save state is for a different ROM"),
+            SnapshotError::WrongPlatform => {
+                write!(f, "The save state is for a different platform")
+            }
+            SnapshotError::Corrupt => write!(f, "The save state is corrupt"),
+        }
+    }
+}
+
+/// The complete state of a machine: the CPU, memory, timers, display and
+/// keypad. Produced by `Cpu::snapshot` and applied by `Cpu::restore`.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub(super) rom_hash: RomHash,
+    pub(super) platform: Platform,
+    pub(super) gpr: [u8; 16],
+    pub(super) rpl_flags: [u8; 16],
+    pub(super) program_counter: usize,
+    pub(super) index: usize,
+    pub(super) stack_pointer: usize,
+    pub(super) stack: Vec<u16>,
+    pub(super) delay_timer: u8,
+    pub(super) sound_timer: u8,
+    pub(super) planes: u8,
+    pub(super) audio_pattern: [u8; PATTERN_SIZE],
+    pub(super) pitch: u8,
+    pub(super) halted: bool,
+    pub(super) memory: Vec<u8>,
+    pub(super) display: Screen,
+    pub(super) keypad: KeyPad,
+}
+
+impl Snapshot {
+    /// Writes the snapshot in the versioned save state format: a header of
+    /// the magic bytes, the format version and the ROM's hash, followed by the
+    /// machine state. Numbers are big-endian.
+    pub fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_u16::<BigEndian>(VERSION)?;
+        writer.write_all(&self.rom_hash)?;
+        writer.write_u8(self.platform.id())?;
+
+        writer.write_all(&self.gpr)?;
+        writer.write_all(&self.rpl_flags)?;
+        writer.write_u32::<BigEndian>(self.program_counter as u32)?;
+        writer.write_u32::<BigEndian>(self.index as u32)?;
+        writer.write_u32::<BigEndian>(self.stack_pointer as u32)?;
+        writer.write_u16::<BigEndian>(self.stack.len() as u16)?;
+        for address in &self.stack {
+            writer.write_u16::<BigEndian>(*address)?;
+        }
+        writer.write_u8(self.delay_timer)?;
+        writer.write_u8(self.sound_timer)?;
+        writer.write_u8(self.planes)?;
+        writer.write_all(&self.audio_pattern)?;
+        writer.write_u8(self.pitch)?;
+        writer.write_u8(self.halted as u8)?;
+
+        writer.write_u32::<BigEndian>(self.memory.len() as u32)?;
+        writer.write_all(&self.memory)?;
+
+        writer.write_u16::<BigEndian>(self.display.width() as u16)?;
+        writer.write_u16::<BigEndian>(self.display.height() as u16)?;
+        for row in self.display.rows() {
+            writer.write_all(row)?;
+        }
+
+        for pressed in self.keypad.iter() {
+            writer.write_u8(*pressed as u8)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from(reader: &mut dyn Read) -> Result<Snapshot, SnapshotError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = reader.read_u16::<BigEndian>()?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut rom_hash = [0; 20];
+        reader.read_exact(&mut rom_hash)?;
+        let platform = Platform::from_id(reader.read_u8()?).ok_or(SnapshotError::Corrupt)?;
+
+        let mut gpr = [0; 16];
+        reader.read_exact(&mut gpr)?;
+        let mut rpl_flags = [0; 16];
+        reader.read_exact(&mut rpl_flags)?;
+        let program_counter = reader.read_u32::<BigEndian>()? as usize;
+        let index = reader.read_u32::<BigEndian>()? as usize;
+        let stack_pointer = reader.read_u32::<BigEndian>()? as usize;
+        let stack_depth = reader.read_u16::<BigEndian>()?;
+        let stack = (0..stack_depth)
+            .map(|_| reader.read_u16::<BigEndian>())
+            .collect::<Result<_, _>>()?;
+        let delay_timer = reader.read_u8()?;
+        let sound_timer = reader.read_u8()?;
+        let planes = reader.read_u8()?;
+        if planes & !0b11 != 0 {
+            return Err(SnapshotError::Corrupt);
+        }
+        let mut audio_pattern = [0; PATTERN_SIZE];
+        reader.read_exact(&mut audio_pattern)?;
+        let pitch = reader.read_u8()?;
+        let halted = reader.read_u8()? != 0;
+
+        let memory_size = reader.read_u32::<BigEndian>()? as usize;
+        if memory_size > XO_MEMORY_SIZE {
+            return Err(SnapshotError::Corrupt);
+        }
+        let mut memory = vec![0; memory_size];
+        reader.read_exact(&mut memory)?;
+
+        let width = reader.read_u16::<BigEndian>()? as usize;
+        let height = reader.read_u16::<BigEndian>()? as usize;
+        if width > HIRES_WIDTH || height > HIRES_HEIGHT {
+            return Err(SnapshotError::Corrupt);
+        }
+        let mut display = Screen::new(width, height);
+        let mut row = vec![0; width];
+        for y in 0..height {
+            reader.read_exact(&mut row)?;
+            for (x, pixel) in row.iter().enumerate() {
+                // A pixel holds one bit per plane.
+                if *pixel > 0b11 {
+                    return Err(SnapshotError::Corrupt);
+                }
+                display.set(x, y, *pixel);
+            }
+        }
+
+        let mut keypad = [false; 16];
+        for pressed in keypad.iter_mut() {
+            *pressed = reader.read_u8()? != 0;
+        }
+
+        Ok(Snapshot {
+            rom_hash,
+            platform,
+            gpr,
+            rpl_flags,
+            program_counter,
+            index,
+            stack_pointer,
+            stack,
+            delay_timer,
+            sound_timer,
+            planes,
+            audio_pattern,
+            pitch,
+            halted,
+            memory,
+            display,
+            keypad,
+        })
+    }
+}
@@ -0,0 +1,42 @@
+use super::memory::Memory;
+use super::user_interface::UI;
+
+/// What `SYS addr` does when no routine is registered at its address. On the
+/// COSMAC VIP, it called a routine in 1802 machine code.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SysMode {
+    /// Does nothing, as most interpreters since the VIP do. `SYS 0` still
+    /// stops the program, as an illegal opcode.
+    Ignore,
+    /// Stops the program with `CpuFault::MachineCodeCall`.
+    Fault,
+}
+
+impl SysMode {
+    pub fn id(self) -> u8 {
+        match self {
+            SysMode::Ignore => 0,
+            SysMode::Fault => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<SysMode> {
+        match id {
+            0 => Some(SysMode::Ignore),
+            1 => Some(SysMode::Fault),
+            _ => None,
+        }
+    }
+}
+
+/// The parts of the machine a machine-code routine can change.
+pub struct SysContext<'a, T: UI> {
+    pub gpr: &'a mut [u8; 16],
+    pub index: &'a mut usize,
+    pub memory: &'a mut Memory,
+    pub ui: &'a mut T,
+}
+
+/// Stands in for the machine-code routine at an address, for the programs that
+/// depend on it.
+pub type SysRoutine<T> = Box<dyn FnMut(&mut SysContext<T>)>;
@@ -0,0 +1,636 @@
+use super::audio::NullSink;
+use super::user_interface::{HeadlessUI, UI};
+use super::*;
+use super::random::{FixedRandom, SeededRandom};
+
+/// About as many instructions as the default clock rate runs per frame.
+const STEPS_PER_FRAME: usize = 12;
+
+fn new_cpu(program: &[u16], platform: Platform, ui: HeadlessUI) -> Cpu<HeadlessUI> {
+    Cpu::new(
+        assemble(program),
+        ui,
+        platform,
+        Quirks::for_platform(platform),
+        TimerMode::Ticked,
+        Box::new(NullSink),
+        Box::new(SeededRandom::new(0)),
+    )
+    .unwrap()
+}
+
+fn new_cpu_with_quirks(program: &[u16], quirks: Quirks) -> Cpu<HeadlessUI> {
+    Cpu::new(
+        assemble(program),
+        HeadlessUI::new(),
+        Platform::Chip8,
+        quirks,
+        TimerMode::Ticked,
+        Box::new(NullSink),
+        Box::new(SeededRandom::new(0)),
+    )
+    .unwrap()
+}
+
+fn assemble(program: &[u16]) -> Vec<u8> {
+    program
+        .iter()
+        .flat_map(|word| word.to_be_bytes().to_vec())
+        .collect()
+}
+
+fn step(cpu: &mut Cpu<HeadlessUI>, steps: usize) {
+    for _ in 0..steps {
+        cpu.execute().unwrap();
+    }
+}
+
+fn run_frames(cpu: &mut Cpu<HeadlessUI>, frames: usize) {
+    for _ in 0..frames {
+        step(cpu, STEPS_PER_FRAME);
+        cpu.end_frame();
+    }
+}
+
+fn lit_pixels(ui: &HeadlessUI, y: usize, xs: std::ops::Range<usize>) -> Vec<usize> {
+    xs.filter(|x| ui.read_pixel(*x, y) != 0).collect()
+}
+
+#[test]
+fn draws_a_sprite() {
+    let mut cpu = new_cpu(
+        &[
+            0xa20a, // MOV I, 0x20a
+            0x6005, // MOV V0, 5
+            0x6103, // MOV V1, 3
+            0xd012, // DRW V0, V1, 2
+            0x1208, // JMP 0x208
+            0xf090, // The sprite.
+        ],
+        Platform::Chip8,
+        HeadlessUI::new(),
+    );
+    run_frames(&mut cpu, 1);
+
+    assert_eq!(lit_pixels(cpu.ui(), 3, 0..16), [5, 6, 7, 8]);
+    assert_eq!(lit_pixels(cpu.ui(), 4, 0..16), [5, 8]);
+    assert_eq!(lit_pixels(cpu.ui(), 5, 0..16), []);
+}
+
+#[test]
+fn drawing_over_a_sprite_erases_it_and_sets_vf() {
+    let mut cpu = new_cpu(
+        &[
+            0xa20e, // MOV I, 0x20e
+            0x6005, // MOV V0, 5
+            0x6103, // MOV V1, 3
+            0xd012, // DRW V0, V1, 2
+            0xd012, // DRW V0, V1, 2
+            0xdff1, // DRW VF, VF, 1
+            0x120c, // JMP 0x20c
+            0xf090, // The sprite.
+        ],
+        Platform::Chip8,
+        HeadlessUI::new(),
+    );
+    run_frames(&mut cpu, 1);
+
+    assert_eq!(lit_pixels(cpu.ui(), 3, 0..16), []);
+    assert_eq!(lit_pixels(cpu.ui(), 4, 0..16), []);
+    // The last sprite is drawn at (VF, VF), so it shows the collision flag.
+    assert_eq!(lit_pixels(cpu.ui(), 1, 0..16), [1, 2, 3, 4]);
+}
+
+#[test]
+fn delay_timer_counts_down_once_per_frame() {
+    let mut cpu = new_cpu(
+        &[
+            0x600a, // MOV V0, 10
+            0xf015, // MOV DT, V0
+            0xf107, // MOV V1, DT
+            0x3100, // SKE V1, 0
+            0x1204, // JMP 0x204
+            0xa212, // MOV I, 0x212
+            0xd001, // DRW V0, V0, 1
+            0x120e, // JMP 0x20e
+            0x0000,
+            0xf000, // The sprite.
+        ],
+        Platform::Chip8,
+        HeadlessUI::new(),
+    );
+    run_frames(&mut cpu, 10);
+    assert_eq!(lit_pixels(cpu.ui(), 10, 0..16), []);
+
+    // The tenth frame brought the timer to 0, which the next frame sees.
+    run_frames(&mut cpu, 1);
+    assert_eq!(lit_pixels(cpu.ui(), 10, 0..16), [10, 11, 12, 13]);
+}
+
+#[test]
+fn waits_for_a_scripted_key() {
+    let mut ui = HeadlessUI::new();
+    ui.script_key(3, 0x7, true);
+    let mut cpu = new_cpu(
+        &[
+            0xf00a, // MOV V0, K
+            0xa20a, // MOV I, 0x20a
+            0xd001, // DRW V0, V0, 1
+            0x1206, // JMP 0x206
+            0x0000,
+            0xf000, // The sprite.
+        ],
+        Platform::Chip8,
+        ui,
+    );
+
+    run_frames(&mut cpu, 2);
+    assert_eq!(lit_pixels(cpu.ui(), 7, 0..16), []);
+
+    run_frames(&mut cpu, 2);
+    assert_eq!(lit_pixels(cpu.ui(), 7, 0..16), [7, 8, 9, 10]);
+}
+
+#[test]
+fn hires_mode_draws_a_16x16_sprite() {
+    let mut program = vec![
+        0x00ff, // HIGH
+        0xa20c, // MOV I, 0x20c
+        0x6064, // MOV V0, 100
+        0x6132, // MOV V1, 50
+        0xd010, // DRW V0, V1, 0
+        0x120a, // JMP 0x20a
+    ];
+    program.extend_from_slice(&[0xffff; 16]);
+    let mut cpu = new_cpu(&program, Platform::SuperChip, HeadlessUI::new());
+    run_frames(&mut cpu, 1);
+
+    assert_eq!(cpu.ui().resolution(), (HIRES_WIDTH, HIRES_HEIGHT));
+    let sprite_columns: Vec<usize> = (100..116).collect();
+    assert_eq!(lit_pixels(cpu.ui(), 49, 96..120), []);
+    assert_eq!(lit_pixels(cpu.ui(), 50, 96..120), sprite_columns);
+    assert_eq!(lit_pixels(cpu.ui(), 63, 96..120), sprite_columns);
+}
+
+#[test]
+fn scrolls_the_display() {
+    let mut cpu = new_cpu(
+        &[
+            0xa20e, // MOV I, 0x20e
+            0x6005, // MOV V0, 5
+            0x6103, // MOV V1, 3
+            0xd011, // DRW V0, V1, 1
+            0x00c2, // SCD 2
+            0x00fb, // SCR
+            0x120c, // JMP 0x20c
+            0xf000, // The sprite.
+        ],
+        Platform::SuperChip,
+        HeadlessUI::new(),
+    );
+    run_frames(&mut cpu, 1);
+
+    assert_eq!(lit_pixels(cpu.ui(), 3, 0..16), []);
+    assert_eq!(lit_pixels(cpu.ui(), 5, 0..16), [9, 10, 11, 12]);
+}
+
+#[test]
+fn exit_halts_the_interpreter() {
+    let mut cpu = new_cpu(&[0x00fd], Platform::SuperChip, HeadlessUI::new());
+    assert!(!cpu.is_halted());
+    step(&mut cpu, 1);
+    assert!(cpu.is_halted());
+}
+
+#[test]
+fn draws_and_clears_each_plane() {
+    let mut cpu = new_cpu(
+        &[
+            0xf301, // PLANE 3
+            0xa210, // MOV I, 0x210
+            0x6000, // MOV V0, 0
+            0xd001, // DRW V0, V0, 1
+            0xf101, // PLANE 1
+            0x00e0, // CLS
+            0x120c, // JMP 0x20c
+            0x0000,
+            0xf03c, // The sprite, one row per plane.
+        ],
+        Platform::XoChip,
+        HeadlessUI::new(),
+    );
+    step(&mut cpu, 4);
+    let pixels = |cpu: &Cpu<HeadlessUI>| -> Vec<u8> {
+        (0..8).map(|x| cpu.ui().read_pixel(x, 0)).collect()
+    };
+    assert_eq!(pixels(&cpu), [1, 1, 3, 3, 2, 2, 0, 0]);
+
+    run_frames(&mut cpu, 1);
+    assert_eq!(pixels(&cpu), [0, 0, 2, 2, 2, 2, 0, 0]);
+}
+
+#[test]
+fn shift_quirk_picks_the_operand() {
+    let program = [
+        0x6010, // MOV V0, 0x10
+        0x6181, // MOV V1, 0x81
+        0x8016, // SHR V0, V1
+    ];
+    let shift = |shift_uses_vy| {
+        let quirks = Quirks {
+            shift_uses_vy,
+            ..Quirks::MODERN
+        };
+        let mut cpu = new_cpu_with_quirks(&program, quirks);
+        step(&mut cpu, 3);
+        (cpu.gpr[0], cpu.gpr[0xf])
+    };
+
+    assert_eq!(shift(true), (0x40, 1));
+    assert_eq!(shift(false), (0x08, 0));
+}
+
+#[test]
+fn load_store_quirk_moves_the_index() {
+    let program = [
+        0xa300, // MOV I, 0x300
+        0xf255, // STR [I], V2
+        0xf265, // LD V2, [I]
+    ];
+    let indexes = |load_store_increments_index| {
+        let quirks = Quirks {
+            load_store_increments_index,
+            ..Quirks::MODERN
+        };
+        let mut cpu = new_cpu_with_quirks(&program, quirks);
+        step(&mut cpu, 2);
+        let after_store = cpu.index;
+        step(&mut cpu, 1);
+        (after_store, cpu.index)
+    };
+
+    assert_eq!(indexes(true), (0x303, 0x306));
+    assert_eq!(indexes(false), (0x300, 0x300));
+}
+
+#[test]
+fn jump_quirk_picks_the_offset_register() {
+    let program = [
+        0x6010, // MOV V0, 0x10
+        0x6220, // MOV V2, 0x20
+        0xb234, // JMP V0, 0x234
+    ];
+    let jump = |jump_uses_vx| {
+        let quirks = Quirks {
+            jump_uses_vx,
+            ..Quirks::MODERN
+        };
+        let mut cpu = new_cpu_with_quirks(&program, quirks);
+        step(&mut cpu, 3);
+        cpu.program_counter
+    };
+
+    assert_eq!(jump(true), 0x254);
+    assert_eq!(jump(false), 0x244);
+}
+
+#[test]
+fn logic_quirk_resets_vf() {
+    let program = [
+        0x6f01, // MOV VF, 1
+        0x8011, // OR V0, V1
+        0x6f01, // MOV VF, 1
+        0x8012, // AND V0, V1
+        0x6f01, // MOV VF, 1
+        0x8013, // XOR V0, V1
+    ];
+    let flags = |logic_resets_vf| {
+        let quirks = Quirks {
+            logic_resets_vf,
+            ..Quirks::MODERN
+        };
+        let mut cpu = new_cpu_with_quirks(&program, quirks);
+        (0..3)
+            .map(|_| {
+                step(&mut cpu, 2);
+                cpu.gpr[0xf]
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(flags(true), [0, 0, 0]);
+    assert_eq!(flags(false), [1, 1, 1]);
+}
+
+#[test]
+fn clip_quirk_stops_sprites_at_the_edge() {
+    let program = [
+        0xa20a, // MOV I, 0x20a
+        0x603e, // MOV V0, 62
+        0x611f, // MOV V1, 31
+        0xd012, // DRW V0, V1, 2
+        0x1208, // JMP 0x208
+        0xf0f0, // The sprite.
+    ];
+    let drawn = |clip_sprites| {
+        let quirks = Quirks {
+            clip_sprites,
+            ..Quirks::MODERN
+        };
+        let mut cpu = new_cpu_with_quirks(&program, quirks);
+        step(&mut cpu, 4);
+        let ui = cpu.ui();
+        (lit_pixels(ui, 31, 0..64), lit_pixels(ui, 0, 0..64))
+    };
+
+    assert_eq!(drawn(true), (vec![62, 63], vec![]));
+    assert_eq!(drawn(false), (vec![0, 1, 62, 63], vec![0, 1, 62, 63]));
+}
+
+#[test]
+fn skip_if_key_ignores_the_high_nibble() {
+    let mut ui = HeadlessUI::new();
+    ui.set_key_pressed(0x5, true);
+    let mut cpu = new_cpu(
+        &[
+            0x6015, // MOV V0, 0x15
+            0xe09e, // SKP V0
+            0x6101, // MOV V1, 1
+            0x1206, // JMP 0x206
+        ],
+        Platform::Chip8,
+        ui,
+    );
+    run_frames(&mut cpu, 1);
+
+    assert_eq!(cpu.gpr[1], 0);
+}
+
+#[test]
+fn calls_nest_until_the_stack_reaches_the_program() {
+    // The call is above the stack, so that the stack can't overwrite it.
+    let mut program = vec![0; 0x681];
+    program[0] = 0x1f00; // JMP 0xf00
+    program[0x680] = 0x2f00; // CALL 0xf00
+    let mut cpu = new_cpu(&program, Platform::Chip8, HeadlessUI::new());
+    step(&mut cpu, 1 + memory::STACK_DEPTH);
+
+    assert_eq!(
+        cpu.execute(),
+        Err(CpuFault::StackOverflow {
+            address: 0xf00,
+            opcode: 0x2f00
+        })
+    );
+}
+
+#[test]
+fn return_without_a_call_underflows() {
+    let mut cpu = new_cpu(&[0x00ee], Platform::Chip8, HeadlessUI::new());
+
+    assert_eq!(
+        cpu.execute(),
+        Err(CpuFault::StackUnderflow {
+            address: 0x200,
+            opcode: 0x00ee
+        })
+    );
+}
+
+fn pixels(ui: &HeadlessUI) -> Vec<u8> {
+    let (width, height) = ui.resolution();
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| ui.read_pixel(x, y))
+        .collect()
+}
+
+/// Draws the font down the display, one glyph further along each time.
+const FONT_WALK: [u16; 7] = [
+    0x6000, // MOV V0, 0
+    0x6100, // MOV V1, 0
+    0xf029, // FONT V0
+    0xd015, // DRW V0, V1, 5
+    0x7001, // ADD V0, 1
+    0x7102, // ADD V1, 2
+    0x1204, // JMP 0x204
+];
+
+#[test]
+fn restoring_a_snapshot_replays_the_same_frames() {
+    let mut cpu = new_cpu(&FONT_WALK, Platform::Chip8, HeadlessUI::new());
+    run_frames(&mut cpu, 2);
+    let snapshot = cpu.snapshot();
+    run_frames(&mut cpu, 3);
+    let registers = cpu.registers();
+    let display = pixels(cpu.ui());
+
+    cpu.restore(&snapshot).unwrap();
+    assert!(cpu.registers() != registers);
+    run_frames(&mut cpu, 3);
+
+    assert!(cpu.registers() == registers);
+    assert_eq!(pixels(cpu.ui()), display);
+}
+
+#[test]
+fn snapshot_survives_a_round_trip_through_bytes() {
+    let mut cpu = new_cpu(&FONT_WALK, Platform::Chip8, HeadlessUI::new());
+    run_frames(&mut cpu, 2);
+    let registers = cpu.registers();
+    let display = pixels(cpu.ui());
+    let mut bytes = Vec::new();
+    cpu.snapshot().write_to(&mut bytes).unwrap();
+
+    let mut restored = new_cpu(&FONT_WALK, Platform::Chip8, HeadlessUI::new());
+    let snapshot = Snapshot::read_from(&mut bytes.as_slice()).unwrap();
+    restored.restore(&snapshot).unwrap();
+
+    assert!(restored.registers() == registers);
+    assert_eq!(pixels(restored.ui()), display);
+}
+
+#[test]
+fn snapshot_of_another_rom_is_rejected() {
+    let snapshot = new_cpu(&FONT_WALK, Platform::Chip8, HeadlessUI::new()).snapshot();
+    let mut cpu = new_cpu(&[0x1200], Platform::Chip8, HeadlessUI::new());
+
+    match cpu.restore(&snapshot) {
+        Err(SnapshotError::WrongRom) => {}
+        _ => panic!("restored a snapshot of another ROM"),
+    }
+}
+
+#[test]
+fn snapshot_with_an_invalid_pixel_is_corrupt() {
+    let cpu = new_cpu(&FONT_WALK, Platform::Chip8, HeadlessUI::new());
+    let mut bytes = Vec::new();
+    cpu.snapshot().write_to(&mut bytes).unwrap();
+    // The last pixel comes just before the 16 keys.
+    let last_pixel = bytes.len() - 17;
+    bytes[last_pixel] = 0b100;
+
+    match Snapshot::read_from(&mut bytes.as_slice()) {
+        Err(SnapshotError::Corrupt) => {}
+        _ => panic!("read a snapshot with an invalid pixel"),
+    }
+}
+
+fn dedicated_stack(depth: usize) -> Quirks {
+    Quirks {
+        stack: Stack::Dedicated(depth),
+        ..Quirks::VIP
+    }
+}
+
+#[test]
+fn presets_keep_the_stack_in_memory() {
+    for quirks in [
+        Quirks::VIP,
+        Quirks::CHIP48,
+        Quirks::SCHIP,
+        Quirks::XOCHIP,
+        Quirks::MODERN,
+    ]
+    .iter()
+    {
+        assert!(quirks.stack == Stack::Memory);
+    }
+}
+
+#[test]
+fn dedicated_stack_returns_from_calls() {
+    let mut cpu = new_cpu_with_quirks(
+        &[
+            0x2206, // CALL 0x206
+            0x6101, // MOV V1, 1
+            0x1204, // JMP 0x204
+            0x00ee, // RET
+        ],
+        dedicated_stack(2),
+    );
+    step(&mut cpu, 3);
+
+    assert_eq!(cpu.gpr[1], 1);
+    assert_eq!(cpu.program_counter, 0x204);
+}
+
+#[test]
+fn dedicated_stack_overflows_at_its_depth_and_stays_out_of_memory() {
+    let mut cpu = new_cpu_with_quirks(&[0x2200], dedicated_stack(2));
+    step(&mut cpu, 2);
+
+    assert_eq!(
+        cpu.execute(),
+        Err(CpuFault::StackOverflow {
+            address: 0x200,
+            opcode: 0x2200
+        })
+    );
+    let below_stack_base = memory::STACK_BASE - 2 * memory::WORD_SIZE..memory::STACK_BASE;
+    let stack_memory = &cpu.memory.bytes()[below_stack_base];
+    assert!(stack_memory.iter().all(|byte| *byte == 0));
+}
+
+#[test]
+fn dedicated_stack_underflows_without_a_call() {
+    let mut cpu = new_cpu_with_quirks(&[0x00ee], dedicated_stack(2));
+
+    assert_eq!(
+        cpu.execute(),
+        Err(CpuFault::StackUnderflow {
+            address: 0x200,
+            opcode: 0x00ee
+        })
+    );
+}
+
+#[test]
+fn snapshot_restores_a_dedicated_stack() {
+    let program = [
+        0x2204, // CALL 0x204
+        0x1202, // JMP 0x202
+        0x00ee, // RET
+    ];
+    let mut cpu = new_cpu_with_quirks(&program, dedicated_stack(4));
+    step(&mut cpu, 1);
+    let snapshot = cpu.snapshot();
+    step(&mut cpu, 1);
+    cpu.restore(&snapshot).unwrap();
+    step(&mut cpu, 1);
+    assert_eq!(cpu.program_counter, 0x202);
+
+    let mut memory_stack_cpu = new_cpu_with_quirks(&program, Quirks::VIP);
+    match memory_stack_cpu.restore(&snapshot) {
+        Err(SnapshotError::Corrupt) => {}
+        _ => panic!("restored a dedicated stack onto a stack in memory"),
+    }
+}
+
+#[test]
+fn rnd_masks_a_random_byte() {
+    let mut cpu = new_cpu(
+        &[
+            0xc00f, // RND V0, 0x0f
+            0xc1f0, // RND V1, 0xf0
+            0xc23c, // RND V2, 0x3c
+            0x1206, // JMP 0x206
+        ],
+        Platform::Chip8,
+        HeadlessUI::new(),
+    );
+    cpu.random = Box::new(FixedRandom::new(&[0xb6, 0x5d, 0xff]));
+    step(&mut cpu, 3);
+
+    assert_eq!(cpu.gpr[0..3], [0x06, 0x50, 0x3c]);
+}
+
+#[test]
+fn zero_opcode_is_illegal_whatever_the_sys_mode() {
+    for &mode in &[SysMode::Ignore, SysMode::Fault] {
+        let mut cpu = new_cpu(&[0x0000], Platform::Chip8, HeadlessUI::new());
+        cpu.set_sys_mode(mode);
+
+        assert_eq!(
+            cpu.execute(),
+            Err(CpuFault::IllegalOpcode {
+                address: 0x200,
+                opcode: 0x0000
+            })
+        );
+    }
+}
+
+#[test]
+fn sys_call_without_a_routine_is_ignored_or_faults() {
+    let mut cpu = new_cpu(&[0x0300], Platform::Chip8, HeadlessUI::new());
+    assert_eq!(cpu.execute(), Ok(StepOutcome::Executed));
+    assert_eq!(cpu.program_counter, 0x202);
+
+    let mut cpu = new_cpu(&[0x0300], Platform::Chip8, HeadlessUI::new());
+    cpu.set_sys_mode(SysMode::Fault);
+    assert_eq!(
+        cpu.execute(),
+        Err(CpuFault::MachineCodeCall {
+            address: 0x200,
+            opcode: 0x0300
+        })
+    );
+}
+
+#[test]
+fn sys_call_runs_the_registered_routine() {
+    let mut cpu = new_cpu(
+        &[
+            0x6005, // MOV V0, 5
+            0x0300, // SYS 0x300
+        ],
+        Platform::Chip8,
+        HeadlessUI::new(),
+    );
+    cpu.set_sys_mode(SysMode::Fault);
+    cpu.register_sys_routine(0x300, Box::new(|context| context.gpr[1] = context.gpr[0] * 2));
+    step(&mut cpu, 2);
+
+    assert_eq!(cpu.gpr[1], 10);
+}
@@ -1,70 +1,140 @@
-use rodio::source::{self, Source};
+use super::audio::{self, AudioSink, PATTERN_SIZE};
 use std::time::{Duration, Instant};
 
+pub const TIMER_RATE: u32 = 60;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TimerMode {
+    /// The timers are 8-bit counters decremented by `tick`, which the emulation
+    /// loop calls once per 60 Hz frame. Runs are reproducible.
+    Ticked,
+    /// The timers count down against the host's wall clock.
+    WallClock,
+}
+
+/// A 60 Hz counter like the delay timer that, while it is above 0, lets the
+/// continuously running tone of the audio sink through.
 pub struct SoundTimer {
-    output_device: rodio::Device,
-    current_sound: Option<rodio::Sink>,
+    mode: TimerMode,
+    countdown: DelayTimer,
+    audio: Box<dyn AudioSink>,
 }
 
 impl SoundTimer {
-    pub fn new() -> SoundTimer {
+    pub fn new(mode: TimerMode, audio: Box<dyn AudioSink>) -> SoundTimer {
         SoundTimer {
-            output_device: rodio::default_output_device().unwrap(),
-            current_sound: None,
+            mode,
+            countdown: DelayTimer::new(mode),
+            audio,
+        }
+    }
+
+    pub fn set(&mut self, value: u8) {
+        self.countdown.set(value);
+        if value == 0 {
+            self.audio.close_gate();
+            return;
         }
+        match self.mode {
+            TimerMode::Ticked => self.audio.open_gate(None),
+            TimerMode::WallClock => {
+                let duration = Duration::from_millis(hz_to_millis(value as f64) as u64);
+                self.audio.open_gate(Some(duration));
+            }
+        }
+    }
+
+    /// Switches the beeper to an XO-CHIP audio pattern.
+    pub fn set_pattern(&mut self, pattern: [u8; PATTERN_SIZE], pitch: u8) {
+        self.audio
+            .set_pattern(pattern, audio::pitch_to_sample_rate(pitch));
+    }
+
+    pub fn get(&self) -> u8 {
+        self.countdown.get()
     }
 
-    pub fn set(&mut self, time: u64) {
-        let source = source::SineWave::new(280u32)
-            .amplify(0.25)
-            .repeat_infinite()
-            .take_duration(Duration::from_millis(hz_to_millis(time as f64) as u64));
-
-        let sink = self
-            .current_sound
-            .get_or_insert(rodio::Sink::new(&self.output_device));
-        if !sink.empty() {
-            *sink = rodio::Sink::new(&self.output_device);
+    pub fn tick(&mut self) {
+        // The frame that just ended sounded with the gate as it was.
+        self.audio.end_frame();
+        if self.mode == TimerMode::Ticked && self.get() > 0 {
+            self.countdown.tick();
+            if self.get() == 0 {
+                self.audio.close_gate();
+            }
         }
-        sink.append(source);
-        sink.play();
     }
 }
 
 pub struct DelayTimer {
-    duration: Duration,
+    mode: TimerMode,
+    value: u8,
     initial: Instant,
 }
 
 impl DelayTimer {
-    pub fn new() -> DelayTimer {
+    pub fn new(mode: TimerMode) -> DelayTimer {
         DelayTimer {
-            duration: Duration::from_secs(0),
+            mode,
+            value: 0,
             initial: Instant::now(),
         }
     }
 
-    pub fn set(&mut self, time: u64) {
-        self.duration = Duration::from_millis(hz_to_millis(time as f64) as u64);
+    pub fn set(&mut self, value: u8) {
+        self.value = value;
         self.initial = Instant::now();
     }
 
-    pub fn get(&self) -> u64 {
-        if let Some(elapsed_time) = self
-            .duration
-            .checked_sub(Instant::now().duration_since(self.initial))
-        {
-            millis_to_hz(elapsed_time.as_micros() as f64) as u64
-        } else {
-            0
+    pub fn get(&self) -> u8 {
+        match self.mode {
+            TimerMode::Ticked => self.value,
+            TimerMode::WallClock => {
+                let elapsed = millis_to_hz(self.initial.elapsed().as_millis() as f64) as u64;
+                u64::from(self.value).saturating_sub(elapsed) as u8
+            }
+        }
+    }
+
+    pub fn tick(&mut self) {
+        if self.mode == TimerMode::Ticked {
+            self.value = self.value.saturating_sub(1);
         }
     }
 }
 
 fn hz_to_millis(hz: f64) -> f64 {
-    hz / 60.0 * 1000.0
+    hz / TIMER_RATE as f64 * 1000.0
+}
+
+fn millis_to_hz(millis: f64) -> f64 {
+    millis / 1000.0 * TIMER_RATE as f64
 }
 
-fn millis_to_hz(hz: f64) -> f64 {
-    hz / 1000.0 * 60.0
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticked_delay_timer_counts_down_once_per_tick() {
+        let mut timer = DelayTimer::new(TimerMode::Ticked);
+        timer.set(2);
+        assert_eq!(timer.get(), 2);
+        timer.tick();
+        assert_eq!(timer.get(), 1);
+        timer.tick();
+        timer.tick();
+        assert_eq!(timer.get(), 0);
+    }
+
+    #[test]
+    fn wall_clock_delay_timer_ignores_ticks() {
+        let mut timer = DelayTimer::new(TimerMode::WallClock);
+        timer.set(60);
+        for _ in 0..30 {
+            timer.tick();
+        }
+        // Far less than the 500 ms it takes to count down 30.
+        assert!(timer.get() > 30);
+    }
 }
@@ -2,48 +2,429 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::MutexGuard;
 
-pub const DISPLAY_HEIGHT: usize = 32;
-pub const DISPLAY_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+pub const LORES_WIDTH: usize = 64;
+
+pub const HIRES_HEIGHT: usize = 64;
+pub const HIRES_WIDTH: usize = 128;
+
+pub const PLANE_COUNT: usize = 2;
 
 pub trait UI {
-    fn read_pixel(&self, x: usize, y: usize) -> bool;
-    fn write_pixel(&mut self, x: usize, y: usize, value: bool);
-    fn clear_display(&mut self);
+    /// Returns a bitmask of the planes in which the pixel is lit.
+    fn read_pixel(&self, x: usize, y: usize) -> u8;
+    fn write_pixel(&mut self, x: usize, y: usize, value: u8);
+    /// Unlights every pixel in the planes selected by the `planes` bitmask.
+    fn clear_display(&mut self, planes: u8);
+    /// Returns the display's `(width, height)` in pixels.
+    fn resolution(&self) -> (usize, usize);
+    /// Switches the display to a new resolution, clearing it.
+    fn set_resolution(&mut self, width: usize, height: usize);
+    /// Shifts the contents of the selected planes by `dx` columns and `dy`
+    /// rows. Pixels scrolled in from outside the display are unlit.
+    fn scroll_display(&mut self, dx: isize, dy: isize, planes: u8);
     fn is_key_pressed(&self, key_code: usize) -> bool;
+    fn set_key_pressed(&mut self, key_code: usize, value: bool);
+
+    /// Called once at the end of every 60 Hz frame.
+    fn end_frame(&mut self) {}
+}
+
+/// A rectangle of pixels on a `Screen`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Region {
+    /// Returns the smallest region that covers both regions.
+    pub fn union(self, other: Region) -> Region {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Region {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+/// The pixels of the display, each a bitmask of the planes it is lit in.
+/// Tracks the region that changed since it was last taken with
+/// `take_dirty_region`, so that consumers only copy what changed.
+#[derive(Clone)]
+pub struct Screen {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+    dirty: Option<Region>,
+}
+
+impl Screen {
+    /// Creates a blank screen, entirely dirty.
+    pub fn new(width: usize, height: usize) -> Screen {
+        Screen {
+            width,
+            height,
+            pixels: vec![0; width * height],
+            dirty: Some(Region {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            }),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: u8) {
+        let pixel = &mut self.pixels[y * self.width + x];
+        if *pixel != value {
+            *pixel = value;
+            self.mark_dirty(Region {
+                x,
+                y,
+                width: 1,
+                height: 1,
+            });
+        }
+    }
+
+    pub fn clear(&mut self, planes: u8) {
+        self.pixels.iter_mut().for_each(|pixel| *pixel &= !planes);
+        self.mark_all_dirty();
+    }
+
+    pub fn rows(&self) -> std::slice::Chunks<'_, u8> {
+        self.pixels.chunks(self.width)
+    }
+
+    pub fn scroll(&mut self, dx: isize, dy: isize, planes: u8) {
+        let old_pixels = self.pixels.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let source_x = x as isize - dx;
+                let source_y = y as isize - dy;
+                let inside = source_x >= 0
+                    && source_y >= 0
+                    && (source_x as usize) < self.width
+                    && (source_y as usize) < self.height;
+
+                let scrolled = if inside {
+                    old_pixels[source_y as usize * self.width + source_x as usize] & planes
+                } else {
+                    0
+                };
+
+                let pixel = &mut self.pixels[y * self.width + x];
+                *pixel = *pixel & !planes | scrolled;
+            }
+        }
+        self.mark_all_dirty();
+    }
+
+    /// Copies `region` from a screen of the same resolution.
+    pub fn copy_region(&mut self, source: &Screen, region: Region) {
+        for y in region.y..region.y + region.height {
+            let start = y * self.width + region.x;
+            let end = start + region.width;
+            self.pixels[start..end].copy_from_slice(&source.pixels[start..end]);
+        }
+        self.mark_dirty(region);
+    }
+
+    /// Returns the region that changed since the last call, and marks the
+    /// screen clean.
+    pub fn take_dirty_region(&mut self) -> Option<Region> {
+        self.dirty.take()
+    }
+
+    fn mark_dirty(&mut self, region: Region) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(region),
+            None => region,
+        });
+    }
+
+    fn mark_all_dirty(&mut self) {
+        self.dirty = Some(Region {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        });
+    }
 }
 
-type Screen = [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
-type KeyPad = [bool; 16];
+pub type KeyPad = [bool; 16];
 
+/// Draws into a screen of its own, and publishes what changed to the shared
+/// `frame` the window renders once per frame, so that drawing never waits for
+/// the window.
 pub struct PistonUI {
-    pub display: Arc<Mutex<Screen>>,
+    display: Screen,
+    pub frame: Arc<Mutex<Screen>>,
     pub keypad: Arc<Mutex<KeyPad>>,
 }
 
 impl PistonUI {
-    pub fn get_display(&self) -> MutexGuard<Screen> {
-        self.display.lock().unwrap()
+    pub fn new(frame: Arc<Mutex<Screen>>, keypad: Arc<Mutex<KeyPad>>) -> PistonUI {
+        let display = frame.lock().unwrap().clone();
+        PistonUI {
+            display,
+            frame,
+            keypad,
+        }
     }
 
-    pub fn set_key_pressed(&mut self, key_code: usize, value: bool) {
-        self.keypad.lock().unwrap()[key_code] = value;
+    /// Returns the last frame published.
+    pub fn get_display(&self) -> MutexGuard<'_, Screen> {
+        self.frame.lock().unwrap()
+    }
+
+    /// Publishes what was drawn since the last call. Called at the end of
+    /// every frame, and whenever the display must be shown mid-frame.
+    pub fn present(&mut self) {
+        let region = match self.display.take_dirty_region() {
+            Some(region) => region,
+            None => return,
+        };
+
+        let mut frame = self.frame.lock().unwrap();
+        if (frame.width(), frame.height()) != self.resolution() {
+            *frame = Screen::new(self.display.width(), self.display.height());
+        }
+        frame.copy_region(&self.display, region);
     }
 }
 
 impl UI for PistonUI {
-    fn read_pixel(&self, x: usize, y: usize) -> bool {
-        self.display.lock().unwrap()[x][y]
+    fn read_pixel(&self, x: usize, y: usize) -> u8 {
+        self.display.get(x, y)
     }
 
-    fn write_pixel(&mut self, x: usize, y: usize, value: bool) {
-        self.display.lock().unwrap()[x][y] = value;
+    fn write_pixel(&mut self, x: usize, y: usize, value: u8) {
+        self.display.set(x, y, value);
     }
 
-    fn clear_display(&mut self) {
-        *self.display.lock().unwrap() = [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+    fn clear_display(&mut self, planes: u8) {
+        self.display.clear(planes);
+    }
+
+    fn resolution(&self) -> (usize, usize) {
+        (self.display.width(), self.display.height())
+    }
+
+    fn set_resolution(&mut self, width: usize, height: usize) {
+        self.display = Screen::new(width, height);
+    }
+
+    fn scroll_display(&mut self, dx: isize, dy: isize, planes: u8) {
+        self.display.scroll(dx, dy, planes);
     }
 
     fn is_key_pressed(&self, key_code: usize) -> bool {
         self.keypad.lock().unwrap()[key_code]
     }
+
+    fn set_key_pressed(&mut self, key_code: usize, value: bool) {
+        self.keypad.lock().unwrap()[key_code] = value;
+    }
+
+    fn end_frame(&mut self) {
+        self.present();
+    }
+}
+
+/// Keeps the display and keypad in memory, for running the CPU without a
+/// window.
+pub struct HeadlessUI {
+    pub display: Screen,
+    pub keypad: KeyPad,
+    frame: u64,
+    script: Vec<ScriptedKey>,
+}
+
+struct ScriptedKey {
+    frame: u64,
+    key_code: usize,
+    pressed: bool,
+}
+
+impl HeadlessUI {
+    pub fn new() -> HeadlessUI {
+        HeadlessUI {
+            display: Screen::new(LORES_WIDTH, LORES_HEIGHT),
+            keypad: [false; 16],
+            frame: 0,
+            script: Vec::new(),
+        }
+    }
+
+    /// Schedules a key to be pressed or released once `frame` frames have
+    /// elapsed since the UI was created.
+    pub fn script_key(&mut self, frame: u64, key_code: usize, pressed: bool) {
+        self.script.push(ScriptedKey {
+            frame,
+            key_code,
+            pressed,
+        });
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+}
+
+impl Default for HeadlessUI {
+    fn default() -> HeadlessUI {
+        HeadlessUI::new()
+    }
+}
+
+impl UI for HeadlessUI {
+    fn read_pixel(&self, x: usize, y: usize) -> u8 {
+        self.display.get(x, y)
+    }
+
+    fn write_pixel(&mut self, x: usize, y: usize, value: u8) {
+        self.display.set(x, y, value);
+    }
+
+    fn clear_display(&mut self, planes: u8) {
+        self.display.clear(planes);
+    }
+
+    fn resolution(&self) -> (usize, usize) {
+        (self.display.width(), self.display.height())
+    }
+
+    fn set_resolution(&mut self, width: usize, height: usize) {
+        self.display = Screen::new(width, height);
+    }
+
+    fn scroll_display(&mut self, dx: isize, dy: isize, planes: u8) {
+        self.display.scroll(dx, dy, planes);
+    }
+
+    fn is_key_pressed(&self, key_code: usize) -> bool {
+        self.keypad[key_code]
+    }
+
+    fn set_key_pressed(&mut self, key_code: usize, value: bool) {
+        self.keypad[key_code] = value;
+    }
+
+    fn end_frame(&mut self) {
+        self.frame += 1;
+
+        let frame = self.frame;
+        let keypad = &mut self.keypad;
+        self.script.retain(|key| {
+            if key.frame <= frame {
+                keypad[key.key_code] = key.pressed;
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: usize, y: usize, width: usize, height: usize) -> Region {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn clean_screen() -> Screen {
+        let mut screen = Screen::new(LORES_WIDTH, LORES_HEIGHT);
+        screen.take_dirty_region();
+        screen
+    }
+
+    #[test]
+    fn new_screen_is_entirely_dirty() {
+        let mut screen = Screen::new(LORES_WIDTH, LORES_HEIGHT);
+        assert_eq!(
+            screen.take_dirty_region(),
+            Some(region(0, 0, LORES_WIDTH, LORES_HEIGHT))
+        );
+        assert_eq!(screen.take_dirty_region(), None);
+    }
+
+    #[test]
+    fn dirty_region_covers_every_changed_pixel() {
+        let mut screen = clean_screen();
+        screen.set(10, 4, 1);
+        screen.set(3, 20, 1);
+        screen.set(12, 6, 1);
+
+        assert_eq!(screen.take_dirty_region(), Some(region(3, 4, 10, 17)));
+        assert_eq!(screen.take_dirty_region(), None);
+    }
+
+    #[test]
+    fn setting_a_pixel_to_its_value_leaves_it_clean() {
+        let mut screen = clean_screen();
+        screen.set(10, 4, 0);
+        assert_eq!(screen.take_dirty_region(), None);
+    }
+
+    #[test]
+    fn clearing_and_scrolling_dirty_the_whole_screen() {
+        let mut screen = clean_screen();
+        screen.clear(1);
+        assert_eq!(
+            screen.take_dirty_region(),
+            Some(region(0, 0, LORES_WIDTH, LORES_HEIGHT))
+        );
+
+        screen.scroll(0, 1, 1);
+        assert_eq!(
+            screen.take_dirty_region(),
+            Some(region(0, 0, LORES_WIDTH, LORES_HEIGHT))
+        );
+    }
+
+    #[test]
+    fn present_publishes_only_the_dirty_region() {
+        let frame = Arc::new(Mutex::new(Screen::new(LORES_WIDTH, LORES_HEIGHT)));
+        let keypad = Arc::new(Mutex::new([false; 16]));
+        let mut ui = PistonUI::new(Arc::clone(&frame), keypad);
+        ui.present();
+        frame.lock().unwrap().take_dirty_region();
+
+        ui.write_pixel(5, 6, 1);
+        ui.write_pixel(7, 6, 3);
+        assert_eq!(ui.get_display().get(5, 6), 0);
+        ui.present();
+
+        let mut frame = frame.lock().unwrap();
+        assert_eq!(frame.get(5, 6), 1);
+        assert_eq!(frame.get(7, 6), 3);
+        assert_eq!(frame.take_dirty_region(), Some(region(5, 6, 3, 1)));
+    }
 }
@@ -0,0 +1,401 @@
+use crate::cpu::user_interface::UI;
+use crate::cpu::Cpu;
+use crate::rewind::RewindBuffer;
+use std::collections::BTreeSet;
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, TryRecvError};
+
+pub const HELP: &str = "\
+commands:
+  c, continue          resume execution
+  p, pause             pause execution
+  s, step [count]      execute count instructions (default 1)
+  u, until <addr>      run until PC reaches addr
+  bk, back [count]     rewind to the start of the count-th last frame (default 1)
+  b, break <addr>      set a breakpoint at addr
+  d, delete <addr>     remove the breakpoint at addr
+  w, watch <addr>      pause on writes to addr
+  uw, unwatch <addr>   remove the watchpoint on addr
+  r, regs              show the registers
+  x <addr> [count]     show count bytes of memory at addr (default 16)
+  l, list              list breakpoints and watchpoints
+  h, help              show this message
+addresses are hexadecimal";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Continue,
+    Pause,
+    Step(NonZeroU32),
+    RunTo(usize),
+    Back(NonZeroU32),
+    Break(usize),
+    Delete(usize),
+    Watch(usize),
+    Unwatch(usize),
+    Registers,
+    Examine(usize, usize),
+    List,
+    Help,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let address = |index: usize| -> Result<usize, String> {
+            let word = words
+                .get(index)
+                .ok_or_else(|| format!("{} needs an address", words[0]))?;
+            usize::from_str_radix(word.trim_start_matches("0x"), 16)
+                .map_err(|_| format!("Invalid address {}", word))
+        };
+
+        let one = NonZeroU32::new(1).unwrap();
+
+        match words.first() {
+            None => Err("Empty command".to_string()),
+            Some(&"c") | Some(&"continue") => Ok(Command::Continue),
+            Some(&"p") | Some(&"pause") => Ok(Command::Pause),
+            Some(&"s") | Some(&"step") => Ok(Command::Step(parse_count(&words, 1, one)?)),
+            Some(&"u") | Some(&"until") => Ok(Command::RunTo(address(1)?)),
+            Some(&"bk") | Some(&"back") => Ok(Command::Back(parse_count(&words, 1, one)?)),
+            Some(&"b") | Some(&"break") => Ok(Command::Break(address(1)?)),
+            Some(&"d") | Some(&"delete") => Ok(Command::Delete(address(1)?)),
+            Some(&"w") | Some(&"watch") => Ok(Command::Watch(address(1)?)),
+            Some(&"uw") | Some(&"unwatch") => Ok(Command::Unwatch(address(1)?)),
+            Some(&"r") | Some(&"regs") => Ok(Command::Registers),
+            Some(&"x") => Ok(Command::Examine(address(1)?, parse_count(&words, 2, 16)?)),
+            Some(&"l") | Some(&"list") => Ok(Command::List),
+            Some(&"h") | Some(&"help") => Ok(Command::Help),
+            Some(word) => Err(format!("Unknown command {}, try help", word)),
+        }
+    }
+}
+
+fn parse_count<T: FromStr>(words: &[&str], index: usize, default: T) -> Result<T, String> {
+    match words.get(index) {
+        Some(word) => word.parse().map_err(|_| format!("Invalid count {}", word)),
+        None => Ok(default),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Running,
+    Paused,
+    /// Executing the given number of instructions, which counts the one about
+    /// to execute and so is never 0.
+    Stepping(NonZeroU32),
+}
+
+/// Controls the execution of a `Cpu` according to commands received over a
+/// channel, typically from a REPL on another thread.
+pub struct Debugger {
+    commands: Receiver<Command>,
+    state: State,
+    breakpoints: BTreeSet<usize>,
+    run_to: Option<usize>,
+}
+
+impl Debugger {
+    /// Creates a debugger that starts with the CPU paused.
+    pub fn new(commands: Receiver<Command>) -> Debugger {
+        Debugger {
+            commands,
+            state: State::Paused,
+            breakpoints: BTreeSet::new(),
+            run_to: None,
+        }
+    }
+
+    /// Handles pending commands, blocking for as long as execution is paused.
+    /// Returns once the CPU may execute its next instruction. `rewind` holds
+    /// the frames the `back` command steps through.
+    pub fn before_step<T: UI>(&mut self, cpu: &mut Cpu<T>, rewind: &mut RewindBuffer) {
+        loop {
+            loop {
+                match self.commands.try_recv() {
+                    Ok(command) => self.handle(command, cpu, rewind),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        // Nobody is left to resume a paused CPU.
+                        self.state = State::Running;
+                        break;
+                    }
+                }
+            }
+
+            match self.state {
+                State::Running | State::Stepping(_) => return,
+                State::Paused => {
+                    if let Ok(command) = self.commands.recv() {
+                        self.handle(command, cpu, rewind);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Pauses execution if the instruction that just executed hit a
+    /// breakpoint, a watchpoint or the end of a step.
+    pub fn after_step<T: UI>(&mut self, cpu: &mut Cpu<T>) {
+        let program_counter = cpu.registers().program_counter;
+        let watch_hits = cpu.memory_mut().take_watch_hits();
+
+        if !watch_hits.is_empty() {
+            for address in watch_hits {
+                println!("Watchpoint: write to {:#06x}", address);
+            }
+            self.pause(cpu);
+        } else if self.breakpoints.contains(&program_counter) {
+            println!("Breakpoint at {:#06x}", program_counter);
+            self.pause(cpu);
+        } else if self.run_to == Some(program_counter) {
+            self.pause(cpu);
+        } else if let State::Stepping(count) = self.state {
+            match NonZeroU32::new(count.get() - 1) {
+                Some(count) => self.state = State::Stepping(count),
+                None => self.pause(cpu),
+            }
+        }
+    }
+
+    fn pause<T: UI>(&mut self, cpu: &Cpu<T>) {
+        self.state = State::Paused;
+        self.run_to = None;
+        show_registers(cpu);
+    }
+
+    fn handle<T: UI>(&mut self, command: Command, cpu: &mut Cpu<T>, rewind: &mut RewindBuffer) {
+        match command {
+            Command::Continue => self.state = State::Running,
+            Command::Pause => self.pause(cpu),
+            Command::Step(count) => self.state = State::Stepping(count),
+            Command::RunTo(address) => {
+                self.run_to = Some(address);
+                self.state = State::Running;
+            }
+            Command::Back(count) => {
+                let snapshot = (0..count.get()).filter_map(|_| rewind.pop()).last();
+                match snapshot.map(|snapshot| cpu.restore(&snapshot)) {
+                    Some(Ok(())) => self.pause(cpu),
+                    Some(Err(error)) => println!("Failed to rewind: {}", error),
+                    None => println!("No frames to rewind"),
+                }
+            }
+            Command::Break(address) => {
+                self.breakpoints.insert(address);
+            }
+            Command::Delete(address) => {
+                if !self.breakpoints.remove(&address) {
+                    println!("No breakpoint at {:#06x}", address);
+                }
+            }
+            Command::Watch(address) => cpu.memory_mut().watch(address),
+            Command::Unwatch(address) => cpu.memory_mut().unwatch(address),
+            Command::Registers => show_registers(cpu),
+            Command::Examine(address, count) => examine(cpu, address, count),
+            Command::List => {
+                for address in &self.breakpoints {
+                    println!("break {:#06x}", address);
+                }
+                for address in cpu.memory().watchpoints() {
+                    println!("watch {:#06x}", address);
+                }
+            }
+            Command::Help => println!("{}", HELP),
+        }
+    }
+}
+
+fn show_registers<T: UI>(cpu: &Cpu<T>) {
+    let registers = cpu.registers();
+    let bytes = cpu.memory().bytes();
+    let program_counter = registers.program_counter;
+
+    println!("{}", registers);
+    if program_counter + 1 < bytes.len() {
+        println!(
+            "next: {:#06x}: {:02x}{:02x}",
+            program_counter,
+            bytes[program_counter],
+            bytes[program_counter + 1]
+        );
+    }
+}
+
+fn examine<T: UI>(cpu: &Cpu<T>, address: usize, count: usize) {
+    let bytes = cpu.memory().bytes();
+    let end = address.saturating_add(count).min(bytes.len());
+    if address >= end {
+        println!("Address {:#06x} is outside memory", address);
+        return;
+    }
+
+    for (line, chunk) in bytes[address..end].chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        println!("{:#06x}: {}", address + line * 16, hex.join(" "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::audio::NullSink;
+    use crate::cpu::user_interface::HeadlessUI;
+    use crate::cpu::{Platform, Quirks, TimerMode};
+    use crate::cpu::random::SeededRandom;
+    use std::sync::mpsc::{self, Sender};
+
+    fn steps(count: u32) -> Command {
+        Command::Step(NonZeroU32::new(count).unwrap())
+    }
+
+    fn frames_back(count: u32) -> Command {
+        Command::Back(NonZeroU32::new(count).unwrap())
+    }
+
+    fn new_cpu(program: &[u16]) -> Cpu<HeadlessUI> {
+        let rom = program
+            .iter()
+            .flat_map(|word| word.to_be_bytes().to_vec())
+            .collect();
+        Cpu::new(
+            rom,
+            HeadlessUI::new(),
+            Platform::Chip8,
+            Quirks::MODERN,
+            TimerMode::Ticked,
+            Box::new(NullSink),
+            Box::new(SeededRandom::new(0)),
+        )
+        .unwrap()
+    }
+
+    /// Sends `commands` to a new debugger. The sender is returned so that the
+    /// debugger doesn't see the channel disconnect and resume.
+    fn new_debugger(commands: Vec<Command>) -> (Debugger, Sender<Command>) {
+        let (sender, receiver) = mpsc::channel();
+        for command in commands {
+            sender.send(command).unwrap();
+        }
+        (Debugger::new(receiver), sender)
+    }
+
+    /// Runs until the debugger pauses, up to `limit` instructions.
+    fn run_until_paused(debugger: &mut Debugger, cpu: &mut Cpu<HeadlessUI>, limit: usize) {
+        let mut rewind = RewindBuffer::new(0);
+        for _ in 0..limit {
+            debugger.before_step(cpu, &mut rewind);
+            cpu.execute().unwrap();
+            debugger.after_step(cpu);
+            if debugger.state == State::Paused {
+                return;
+            }
+        }
+        panic!("The debugger didn't pause");
+    }
+
+    const PROGRAM: [u16; 5] = [
+        0x6001, // MOV V0, 1
+        0x6102, // MOV V1, 2
+        0xa300, // MOV I, 0x300
+        0xf155, // STR [I], V1
+        0x1208, // JMP 0x208
+    ];
+
+    #[test]
+    fn parses_commands_and_their_aliases() {
+        assert_eq!(Command::parse("c"), Ok(Command::Continue));
+        assert_eq!(Command::parse("pause"), Ok(Command::Pause));
+        assert_eq!(Command::parse("s"), Ok(steps(1)));
+        assert_eq!(Command::parse("step 20"), Ok(steps(20)));
+        assert_eq!(Command::parse("u 0x2a0"), Ok(Command::RunTo(0x2a0)));
+        assert_eq!(Command::parse("bk"), Ok(frames_back(1)));
+        assert_eq!(Command::parse("back 60"), Ok(frames_back(60)));
+        assert_eq!(Command::parse("  b   2a0 "), Ok(Command::Break(0x2a0)));
+        assert_eq!(Command::parse("delete 2A0"), Ok(Command::Delete(0x2a0)));
+        assert_eq!(Command::parse("w 300"), Ok(Command::Watch(0x300)));
+        assert_eq!(Command::parse("uw 300"), Ok(Command::Unwatch(0x300)));
+        assert_eq!(Command::parse("x 300"), Ok(Command::Examine(0x300, 16)));
+        assert_eq!(Command::parse("x 300 4"), Ok(Command::Examine(0x300, 4)));
+        assert_eq!(Command::parse("regs"), Ok(Command::Registers));
+        assert_eq!(Command::parse("l"), Ok(Command::List));
+        assert_eq!(Command::parse("help"), Ok(Command::Help));
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        assert!(Command::parse("").is_err());
+        assert!(Command::parse("jump 200").is_err());
+        assert!(Command::parse("b").is_err());
+        assert!(Command::parse("b 2g0").is_err());
+        assert!(Command::parse("x 300 -1").is_err());
+        // Counts are parsed as they are stored, rather than truncated.
+        assert!(Command::parse("s 0").is_err());
+        assert!(Command::parse("s 4294967296").is_err());
+        assert!(Command::parse("back 0").is_err());
+        assert!(Command::parse("back 4294967296").is_err());
+    }
+
+    #[test]
+    fn examining_past_the_end_of_memory_is_harmless() {
+        let cpu = new_cpu(&PROGRAM);
+        examine(&cpu, usize::MAX, 16);
+        examine(&cpu, 0xff0, usize::MAX);
+    }
+
+    #[test]
+    fn steps_the_given_number_of_instructions() {
+        let mut cpu = new_cpu(&PROGRAM);
+        let (mut debugger, _sender) = new_debugger(vec![steps(3)]);
+
+        run_until_paused(&mut debugger, &mut cpu, 3);
+        assert_eq!(cpu.registers().program_counter, 0x206);
+    }
+
+    #[test]
+    fn pauses_at_a_breakpoint() {
+        let mut cpu = new_cpu(&PROGRAM);
+        let (mut debugger, _sender) = new_debugger(vec![Command::Break(0x204), Command::Continue]);
+
+        run_until_paused(&mut debugger, &mut cpu, 10);
+        assert_eq!(cpu.registers().program_counter, 0x204);
+    }
+
+    #[test]
+    fn pauses_after_a_write_to_a_watched_address() {
+        let mut cpu = new_cpu(&PROGRAM);
+        let (mut debugger, _sender) = new_debugger(vec![Command::Watch(0x301), Command::Continue]);
+
+        run_until_paused(&mut debugger, &mut cpu, 10);
+        assert_eq!(cpu.registers().program_counter, 0x208);
+    }
+
+    #[test]
+    fn runs_until_an_address() {
+        let mut cpu = new_cpu(&PROGRAM);
+        let (mut debugger, _sender) = new_debugger(vec![Command::RunTo(0x206)]);
+
+        run_until_paused(&mut debugger, &mut cpu, 10);
+        assert_eq!(cpu.registers().program_counter, 0x206);
+        assert_eq!(debugger.run_to, None);
+    }
+
+    #[test]
+    fn steps_back_the_given_number_of_frames() {
+        let mut cpu = new_cpu(&PROGRAM);
+        let mut rewind = RewindBuffer::new(10);
+        for _ in 0..3 {
+            rewind.push(&cpu.snapshot());
+            cpu.execute().unwrap();
+        }
+        let (mut debugger, _sender) = new_debugger(vec![frames_back(2)]);
+
+        debugger.handle(debugger.commands.try_recv().unwrap(), &mut cpu, &mut rewind);
+        assert_eq!(cpu.registers().program_counter, 0x202);
+        assert_eq!(debugger.state, State::Paused);
+        assert_eq!(rewind.len(), 1);
+    }
+}
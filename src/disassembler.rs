@@ -0,0 +1,219 @@
+use crate::cpu::memory::PROGRAM_CODE_BASE;
+use crate::cpu::opcode::{Instruction, Opcode};
+use crate::cpu::Platform;
+use std::collections::{BTreeMap, BTreeSet};
+
+const LONG_LOAD_OPCODE: u16 = 0xf000;
+const DATA_BYTES_PER_LINE: usize = 8;
+
+enum Flow {
+    Continue,
+    /// A conditional skip, which continues at either of the next two
+    /// instructions.
+    Skip,
+    Jump(usize),
+    Call(usize),
+    Stop,
+}
+
+struct Decoded {
+    text: String,
+    size: usize,
+    flow: Flow,
+}
+
+/// Disassembles a ROM loaded at `PROGRAM_CODE_BASE` into a listing, reading
+/// instructions as `platform` does.
+///
+/// Only bytes reachable from the entry point are decoded as instructions: the
+/// tracer follows jumps, calls and both sides of every skip, and everything it
+/// never reaches is listed as `db` data. Jump and call targets get generated
+/// labels. Addresses and raw words are listed in comments, so the listing can
+/// be fed back to the assembler.
+pub fn disassemble(rom: &[u8], platform: Platform) -> String {
+    let (code, targets) = trace(rom, platform);
+    let labels: BTreeMap<usize, String> = targets
+        .into_iter()
+        .filter(|(address, _)| code.contains_key(address))
+        .collect();
+    let format_address = |address: usize| match labels.get(&address) {
+        Some(label) => label.clone(),
+        None => format!("{:#05x}", address),
+    };
+
+    let mut listing = String::new();
+    let mut address = PROGRAM_CODE_BASE;
+    let end = PROGRAM_CODE_BASE + rom.len();
+    while address < end {
+        if let Some(label) = labels.get(&address) {
+            listing.push_str(&format!("{}:\n", label));
+        }
+
+        let offset = address - PROGRAM_CODE_BASE;
+        if code.contains_key(&address) {
+            let decoded = decode(rom, offset, platform, &format_address).unwrap();
+            let raw: Vec<String> = rom[offset..offset + decoded.size]
+                .chunks(2)
+                .map(|word| format!("{:02x}{:02x}", word[0], word[1]))
+                .collect();
+            listing.push_str(&format!(
+                "    {:<28}; {:#05x}: {}\n",
+                decoded.text,
+                address,
+                raw.join(" ")
+            ));
+            address += decoded.size;
+        } else {
+            let data_end = (address + 1..end)
+                .take(DATA_BYTES_PER_LINE - 1)
+                .find(|next| code.contains_key(next) || labels.contains_key(next))
+                .unwrap_or_else(|| (address + DATA_BYTES_PER_LINE).min(end));
+            let bytes: Vec<String> = rom[offset..data_end - PROGRAM_CODE_BASE]
+                .iter()
+                .map(|byte| format!("{:#04x}", byte))
+                .collect();
+            listing.push_str(&format!(
+                "    {:<28}; {:#05x}\n",
+                format!("db {}", bytes.join(", ")),
+                address
+            ));
+            address = data_end;
+        }
+    }
+
+    listing
+}
+
+/// Follows the control flow from the entry point. Returns the address and
+/// size of every instruction reached, and the labels for jump and call
+/// targets.
+fn trace(rom: &[u8], platform: Platform) -> (BTreeMap<usize, usize>, BTreeMap<usize, String>) {
+    let mut code = BTreeMap::new();
+    let mut claimed = BTreeSet::new();
+    let mut targets = BTreeMap::new();
+    let mut pending = vec![PROGRAM_CODE_BASE];
+    let end = PROGRAM_CODE_BASE + rom.len();
+
+    while let Some(address) = pending.pop() {
+        if address < PROGRAM_CODE_BASE || address >= end || code.contains_key(&address) {
+            continue;
+        }
+
+        let offset = address - PROGRAM_CODE_BASE;
+        let decoded = match decode(rom, offset, platform, &|address| address.to_string()) {
+            Some(decoded) => decoded,
+            None => continue,
+        };
+        // An instruction overlapping one that was already decoded would make
+        // the listing ambiguous, so the first interpretation wins.
+        if (address..address + decoded.size).any(|byte| claimed.contains(&byte)) {
+            continue;
+        }
+        claimed.extend(address..address + decoded.size);
+        code.insert(address, decoded.size);
+
+        let next = address + decoded.size;
+        match decoded.flow {
+            Flow::Continue => pending.push(next),
+            Flow::Skip => {
+                pending.push(next);
+                pending.push(next + instruction_size(rom, next - PROGRAM_CODE_BASE, platform));
+            }
+            Flow::Jump(target) => {
+                targets
+                    .entry(target)
+                    .or_insert_with(|| format!("loc_{:03x}", target));
+                pending.push(target);
+            }
+            Flow::Call(target) => {
+                targets.insert(target, format!("sub_{:03x}", target));
+                pending.push(target);
+                pending.push(next);
+            }
+            Flow::Stop => {}
+        }
+    }
+
+    (code, targets)
+}
+
+fn read_word(rom: &[u8], offset: usize) -> Option<u16> {
+    rom.get(offset..offset + 2)
+        .map(|word| u16::from(word[0]) << 8 | u16::from(word[1]))
+}
+
+fn instruction_size(rom: &[u8], offset: usize, platform: Platform) -> usize {
+    if platform == Platform::XoChip && read_word(rom, offset) == Some(LONG_LOAD_OPCODE) {
+        4
+    } else {
+        2
+    }
+}
+
+fn decode(
+    rom: &[u8],
+    offset: usize,
+    platform: Platform,
+    format_address: &dyn Fn(usize) -> String,
+) -> Option<Decoded> {
+    let instruction = Opcode::decode(read_word(rom, offset)?, platform);
+    let (text, flow) = match instruction {
+        Instruction::Return | Instruction::Exit => (instruction.to_string(), Flow::Stop),
+        Instruction::Jump { addr } => (format!("JMP {}", format_address(addr)), Flow::Jump(addr)),
+        Instruction::Call { addr } => (format!("CALL {}", format_address(addr)), Flow::Call(addr)),
+        // The real target depends on V0, so the tracer can't follow it.
+        Instruction::JumpOffset { .. } => (instruction.to_string(), Flow::Stop),
+        Instruction::SetIndexLong => {
+            let address = read_word(rom, offset + 2)?;
+            return Some(Decoded {
+                text: format!("{} {:#06x}", instruction, address),
+                size: 4,
+                flow: Flow::Continue,
+            });
+        }
+        Instruction::Illegal { .. } => return None,
+        _ if instruction.is_skip() => (instruction.to_string(), Flow::Skip),
+        _ => (instruction.to_string(), Flow::Continue),
+    };
+
+    Some(Decoded {
+        text,
+        size: 2,
+        flow,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_branch_and_subroutine_targets() {
+        let rom = [
+            0x22, 0x08, // CALL 0x208
+            0x12, 0x06, // JMP 0x206
+            0xab, 0xcd, // Never reached.
+            0x12, 0x06, // JMP 0x206
+            0x30, 0x01, // SKE V0, 1
+            0x00, 0xe0, // CLS
+            0x00, 0xee, // RET
+        ];
+        let listing = [
+            "    CALL sub_208                ; 0x200: 2208",
+            "    JMP loc_206                 ; 0x202: 1206",
+            "    db 0xab, 0xcd               ; 0x204",
+            "loc_206:",
+            "    JMP loc_206                 ; 0x206: 1206",
+            "sub_208:",
+            "    SKE V0, 0x01                ; 0x208: 3001",
+            "    CLS                         ; 0x20a: 00e0",
+            "    RET                         ; 0x20c: 00ee",
+        ];
+        for platform in &[Platform::Chip8, Platform::SuperChip, Platform::XoChip] {
+            assert_eq!(
+                disassemble(&rom, *platform).lines().collect::<Vec<_>>(),
+                listing
+            );
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use piston_window::{
+    Button, ButtonArgs, ButtonState, ControllerAxisArgs, ControllerButton, ControllerHat, Event,
+    HatState, Input, Motion,
+};
+use sdl2::controller::{Axis, Button as PadButton, GameController};
+use sdl2::event::Event as SdlEvent;
+use sdl2::{EventPump, GameControllerSubsystem, Sdl};
+
+/// The buttons reported as `Button<n>`, numbered as SDL numbers them: A, B, X
+/// and Y are 0 to 3.
+const BUTTONS: [PadButton; 11] = [
+    PadButton::A,
+    PadButton::B,
+    PadButton::X,
+    PadButton::Y,
+    PadButton::Back,
+    PadButton::Guide,
+    PadButton::Start,
+    PadButton::LeftStick,
+    PadButton::RightStick,
+    PadButton::LeftShoulder,
+    PadButton::RightShoulder,
+];
+
+const AXES: [Axis; 6] = [
+    Axis::LeftX,
+    Axis::LeftY,
+    Axis::RightX,
+    Axis::RightY,
+    Axis::TriggerLeft,
+    Axis::TriggerRight,
+];
+
+/// Reads game controllers through SDL, as the window's backend reports none,
+/// and turns what they do into the window's controller events. SDL's
+/// controller database lays every known controller out alike: the d-pad is
+/// reported as a hat, the face buttons as `Button0` to `Button3`, and the left
+/// stick as axes 0 and 1, pushed negative for left and up.
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+    events: EventPump,
+    controllers: Vec<GameController>,
+    // Dropped last, as SDL shuts down with it.
+    _sdl: Sdl,
+}
+
+impl Gamepads {
+    pub fn open() -> Result<Gamepads, String> {
+        let sdl = sdl2::init()?;
+        let subsystem = sdl.game_controller()?;
+        let events = sdl.event_pump()?;
+        // Controllers already connected are announced as added by the first
+        // poll, like the ones connected later.
+        Ok(Gamepads {
+            subsystem,
+            events,
+            controllers: Vec::new(),
+            _sdl: sdl,
+        })
+    }
+
+    /// Returns the events of the controllers since the last poll, opening the
+    /// ones connected meanwhile.
+    pub fn poll(&mut self) -> Vec<Event> {
+        let mut events = Vec::new();
+        for event in self.events.poll_iter().collect::<Vec<_>>() {
+            match event {
+                SdlEvent::ControllerDeviceAdded { which, .. } => {
+                    if !self.subsystem.is_game_controller(which) {
+                        continue;
+                    }
+                    match self.subsystem.open(which) {
+                        Ok(controller) => {
+                            println!("Controller connected: {}", controller.name());
+                            self.controllers.push(controller);
+                        }
+                        Err(error) => println!("Failed to open controller: {}", error),
+                    }
+                }
+                SdlEvent::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers
+                        .retain(|controller| controller.instance_id() != which);
+                    // Release whatever it held, so that no key sticks.
+                    events.push(hat_event(which, HatState::Centered));
+                    for (index, _) in BUTTONS.iter().enumerate() {
+                        events.push(button_event(which, index as u8, ButtonState::Release));
+                    }
+                    for (index, _) in AXES.iter().enumerate() {
+                        events.push(axis_event(which, index as u8, 0));
+                    }
+                }
+                SdlEvent::ControllerButtonDown { which, button, .. }
+                | SdlEvent::ControllerButtonUp { which, button, .. }
+                    if is_dpad(button) =>
+                {
+                    events.push(hat_event(which, self.dpad_state(which)));
+                }
+                SdlEvent::ControllerButtonDown { which, button, .. } => {
+                    if let Some(index) = BUTTONS.iter().position(|other| *other == button) {
+                        events.push(button_event(which, index as u8, ButtonState::Press));
+                    }
+                }
+                SdlEvent::ControllerButtonUp { which, button, .. } => {
+                    if let Some(index) = BUTTONS.iter().position(|other| *other == button) {
+                        events.push(button_event(which, index as u8, ButtonState::Release));
+                    }
+                }
+                SdlEvent::ControllerAxisMotion {
+                    which, axis, value, ..
+                } => {
+                    if let Some(index) = AXES.iter().position(|other| *other == axis) {
+                        events.push(axis_event(which, index as u8, value));
+                    }
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+
+    /// Returns the directions held on the d-pad of a controller, diagonals
+    /// included.
+    fn dpad_state(&self, id: i32) -> HatState {
+        let controller = match self
+            .controllers
+            .iter()
+            .find(|controller| controller.instance_id() == id)
+        {
+            Some(controller) => controller,
+            None => return HatState::Centered,
+        };
+        let held = |button| controller.button(button);
+        let vertical = match (held(PadButton::DPadUp), held(PadButton::DPadDown)) {
+            (true, false) => Some(HatState::Up),
+            (false, true) => Some(HatState::Down),
+            _ => None,
+        };
+        let horizontal = match (held(PadButton::DPadLeft), held(PadButton::DPadRight)) {
+            (true, false) => Some(HatState::Left),
+            (false, true) => Some(HatState::Right),
+            _ => None,
+        };
+        match (horizontal, vertical) {
+            (Some(HatState::Left), Some(HatState::Up)) => HatState::LeftUp,
+            (Some(HatState::Left), Some(_)) => HatState::LeftDown,
+            (Some(HatState::Right), Some(HatState::Up)) => HatState::RightUp,
+            (Some(_), Some(_)) => HatState::RightDown,
+            (Some(direction), None) | (None, Some(direction)) => direction,
+            (None, None) => HatState::Centered,
+        }
+    }
+}
+
+fn is_dpad(button: PadButton) -> bool {
+    matches!(
+        button,
+        PadButton::DPadUp | PadButton::DPadDown | PadButton::DPadLeft | PadButton::DPadRight
+    )
+}
+
+/// A centered d-pad is reported as the hat being released.
+fn hat_event(id: i32, state: HatState) -> Event {
+    let button_state = match state {
+        HatState::Centered => ButtonState::Release,
+        _ => ButtonState::Press,
+    };
+    input_button(Button::Hat(ControllerHat::new(id, 0, state)), button_state)
+}
+
+fn button_event(id: i32, button: u8, state: ButtonState) -> Event {
+    input_button(Button::Controller(ControllerButton::new(id, button)), state)
+}
+
+fn axis_event(id: i32, axis: u8, value: i16) -> Event {
+    let position = f64::from(value) / f64::from(i16::MAX);
+    Event::Input(Input::Move(Motion::ControllerAxis(
+        ControllerAxisArgs::new(id, axis, position),
+    )))
+}
+
+fn input_button(button: Button, state: ButtonState) -> Event {
+    Event::Input(Input::Button(ButtonArgs {
+        state,
+        button,
+        scancode: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::Keymap;
+    use chip8::cpu::user_interface::{HeadlessUI, UI};
+
+    fn pressed_keys(ui: &HeadlessUI) -> Vec<usize> {
+        (0..16).filter(|key| ui.is_key_pressed(*key)).collect()
+    }
+
+    #[test]
+    fn default_profile_moves_with_the_dpad() {
+        let mut keymap = Keymap::from_preset("qwerty").unwrap();
+        let mut ui = HeadlessUI::new();
+
+        keymap.handle_event(&hat_event(0, HatState::Up), &mut ui);
+        assert_eq!(pressed_keys(&ui), [0x2]);
+        keymap.handle_event(&hat_event(0, HatState::LeftDown), &mut ui);
+        assert_eq!(pressed_keys(&ui), [0x4, 0x8]);
+        keymap.handle_event(&hat_event(0, HatState::Right), &mut ui);
+        assert_eq!(pressed_keys(&ui), [0x6]);
+        keymap.handle_event(&hat_event(0, HatState::Centered), &mut ui);
+        assert!(pressed_keys(&ui).is_empty());
+    }
+
+    #[test]
+    fn default_profile_moves_with_the_left_stick() {
+        let mut keymap = Keymap::from_preset("qwerty").unwrap();
+        let mut ui = HeadlessUI::new();
+
+        keymap.handle_event(&axis_event(0, 0, i16::MIN), &mut ui);
+        assert_eq!(pressed_keys(&ui), [0x4]);
+        keymap.handle_event(&axis_event(0, 1, i16::MAX), &mut ui);
+        assert_eq!(pressed_keys(&ui), [0x4, 0x8]);
+        // Within the dead zone, the stick counts as centered.
+        keymap.handle_event(&axis_event(0, 0, 1000), &mut ui);
+        keymap.handle_event(&axis_event(0, 1, -1000), &mut ui);
+        assert!(pressed_keys(&ui).is_empty());
+    }
+
+    #[test]
+    fn default_profile_fires_with_the_a_button() {
+        let mut keymap = Keymap::from_preset("qwerty").unwrap();
+        let mut ui = HeadlessUI::new();
+
+        keymap.handle_event(&button_event(0, 0, ButtonState::Press), &mut ui);
+        assert_eq!(pressed_keys(&ui), [0x5]);
+        keymap.handle_event(&button_event(0, 0, ButtonState::Release), &mut ui);
+        assert!(pressed_keys(&ui).is_empty());
+    }
+}
@@ -0,0 +1,523 @@
+use crate::config::check_fields;
+use chip8::cpu::user_interface::UI;
+use piston_window::{Button, GenericEvent, HatState, Key};
+use std::collections::{HashMap, HashSet};
+use toml::value::{Table, Value};
+
+pub const PRESET_NAMES: [&str; 3] = ["qwerty", "azerty", "dvorak"];
+
+/// How far a stick must be pushed along an axis to press the key bound to
+/// that direction.
+const AXIS_THRESHOLD: f64 = 0.5;
+
+/// The CHIP-8 keys as laid out on the COSMAC VIP's keypad, row by row. The
+/// presets list their physical keys in the same order.
+const KEYPAD_LAYOUT: [usize; 16] = [
+    0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF,
+];
+
+#[rustfmt::skip]
+const QWERTY: [Key; 16] = [
+    Key::D1, Key::D2, Key::D3, Key::D4,
+    Key::Q, Key::W, Key::E, Key::R,
+    Key::A, Key::S, Key::D, Key::F,
+    Key::Z, Key::X, Key::C, Key::V,
+];
+
+#[rustfmt::skip]
+const AZERTY: [Key; 16] = [
+    Key::D1, Key::D2, Key::D3, Key::D4,
+    Key::A, Key::Z, Key::E, Key::R,
+    Key::Q, Key::S, Key::D, Key::F,
+    Key::W, Key::X, Key::C, Key::V,
+];
+
+#[rustfmt::skip]
+const DVORAK: [Key; 16] = [
+    Key::D1, Key::D2, Key::D3, Key::D4,
+    Key::Quote, Key::Comma, Key::Period, Key::P,
+    Key::A, Key::O, Key::E, Key::U,
+    Key::Semicolon, Key::Q, Key::J, Key::K,
+];
+
+/// The default controller profile: the d-pad and the left stick move with
+/// 2/4/6/8, the direction keys most games use, and the A button is 5. See
+/// `Gamepads` for how controllers are laid out.
+const GAMEPAD: [(Control, usize); 9] = [
+    (Control::Hat(HatState::Up), 0x2),
+    (Control::Hat(HatState::Left), 0x4),
+    (Control::Hat(HatState::Right), 0x6),
+    (Control::Hat(HatState::Down), 0x8),
+    (Control::Axis(1, false), 0x2),
+    (Control::Axis(0, false), 0x4),
+    (Control::Axis(0, true), 0x6),
+    (Control::Axis(1, true), 0x8),
+    (Control::GamepadButton(0), 0x5),
+];
+
+/// Something on a keyboard or controller that can be bound to a CHIP-8 key.
+/// Controls of every connected controller are treated alike.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Control {
+    Key(Key),
+    GamepadButton(u8),
+    /// One of the d-pad's four directions. The diagonals hold two of them.
+    Hat(HatState),
+    /// An axis pushed in the positive (true) or negative direction.
+    Axis(u8, bool),
+}
+
+impl Control {
+    fn is_key(self) -> bool {
+        matches!(self, Control::Key(_))
+    }
+}
+
+/// Maps the keyboard and controllers to the CHIP-8 keypad. Several controls
+/// may be bound to the same CHIP-8 key, which stays pressed while any of them
+/// is held.
+///
+/// The config file picks a keyboard preset and adds bindings on top of it, for
+/// the keyboard in `keys` and for controllers in `gamepad`, and may override
+/// all of them for a ROM, by the ROM's file name without its extension:
+///
+/// ```toml
+/// preset = "azerty"
+///
+/// [keys]
+/// 5 = ["Z", "Up"]
+///
+/// [roms.INVADERS]
+/// keys = { 4 = "Left", 5 = ["Space", "Up"], 6 = "Right" }
+/// gamepad = { 4 = ["Left", "Axis0-"], 5 = "Button0", 6 = ["Right", "Axis0+"] }
+/// ```
+///
+/// Keys are named as in piston's `Key`, ignoring case, and digits may also be
+/// written without the leading `D`. Controls are named `Up`, `Down`, `Left`
+/// and `Right` for the d-pad, `Button<n>` for buttons, and `Axis<n>+` or
+/// `Axis<n>-` for a direction along an axis. Binding a control takes it away
+/// from whichever CHIP-8 key it was bound to, and listing a CHIP-8 key in
+/// `keys` or `gamepad` replaces all of its keyboard or controller bindings.
+pub struct Keymap {
+    bindings: HashMap<Control, usize>,
+    held: HashSet<Control>,
+}
+
+impl Keymap {
+    pub fn from_preset(name: &str) -> Option<Keymap> {
+        let keys = match name {
+            "qwerty" => QWERTY,
+            "azerty" => AZERTY,
+            "dvorak" => DVORAK,
+            _ => return None,
+        };
+
+        Some(Keymap {
+            bindings: keys
+                .iter()
+                .map(|key| Control::Key(*key))
+                .zip(KEYPAD_LAYOUT.iter().cloned())
+                .chain(GAMEPAD.iter().cloned())
+                .collect(),
+            held: HashSet::new(),
+        })
+    }
+
+    /// Reads the keymap settings of a config file, the top-level `preset`,
+    /// `keys` and `gamepad`, overridden by those in `roms.<rom_name>`. The
+    /// sections of other ROMs are only validated. In between, `rom_keys`
+    /// binds the keys the ROM database suggests, as long as the user hasn't
+    /// bound them to anything else.
+    pub fn from_config(
+        config: &Table,
+        rom_name: &str,
+        rom_keys: &[(usize, String)],
+        errors: &mut Vec<String>,
+    ) -> Keymap {
+        let mut keymap = Keymap::from_preset("qwerty").unwrap();
+        keymap.apply(config, "", errors);
+        for (key_code, name) in rom_keys {
+            if let Some(control) = key_from_name(name) {
+                keymap.bindings.entry(control).or_insert(*key_code);
+            }
+        }
+
+        match config.get("roms") {
+            Some(Value::Table(roms)) => {
+                for (name, rom) in roms {
+                    let path = format!("roms.{}.", name);
+                    let rom = match rom {
+                        Value::Table(rom) => rom,
+                        _ => {
+                            errors.push(format!("{} must be a table", &path[..path.len() - 1]));
+                            continue;
+                        }
+                    };
+                    check_fields(rom, &["preset", "keys", "gamepad"], &path, errors);
+                    if name == rom_name {
+                        keymap.apply(rom, &path, errors);
+                    } else {
+                        Keymap::from_preset("qwerty")
+                            .unwrap()
+                            .apply(rom, &path, errors);
+                    }
+                }
+            }
+            Some(_) => errors.push("roms must be a table".to_string()),
+            None => {}
+        }
+
+        keymap
+    }
+
+    /// Presses and releases the keys of `ui` bound to the controls that
+    /// `event` presses and releases.
+    pub fn handle_event<E: GenericEvent>(&mut self, event: &E, ui: &mut dyn UI) {
+        let was_pressed = self.pressed_keys();
+
+        match event.press_args() {
+            Some(Button::Keyboard(key)) => {
+                self.held.insert(Control::Key(key));
+            }
+            Some(Button::Controller(button)) => {
+                self.held.insert(Control::GamepadButton(button.button));
+            }
+            Some(Button::Hat(hat)) => {
+                self.held
+                    .retain(|control| !matches!(control, Control::Hat(_)));
+                self.held
+                    .extend(hat_directions(hat.state).map(Control::Hat));
+            }
+            _ => {}
+        }
+        match event.release_args() {
+            Some(Button::Keyboard(key)) => {
+                self.held.remove(&Control::Key(key));
+            }
+            Some(Button::Controller(button)) => {
+                self.held.remove(&Control::GamepadButton(button.button));
+            }
+            Some(Button::Hat(_)) => self
+                .held
+                .retain(|control| !matches!(control, Control::Hat(_))),
+            _ => {}
+        }
+        if let Some(args) = event.controller_axis_args() {
+            self.held.remove(&Control::Axis(args.axis, false));
+            self.held.remove(&Control::Axis(args.axis, true));
+            if args.position.abs() >= AXIS_THRESHOLD {
+                self.held
+                    .insert(Control::Axis(args.axis, args.position > 0.0));
+            }
+        }
+
+        let pressed = self.pressed_keys();
+        for key_code in 0..16 {
+            if pressed[key_code] != was_pressed[key_code] {
+                ui.set_key_pressed(key_code, pressed[key_code]);
+            }
+        }
+    }
+
+    fn pressed_keys(&self) -> [bool; 16] {
+        let mut pressed = [false; 16];
+        for control in &self.held {
+            if let Some(key_code) = self.bindings.get(control) {
+                pressed[*key_code] = true;
+            }
+        }
+        pressed
+    }
+
+    /// Applies the `preset`, `keys` and `gamepad` fields of a config table.
+    /// `path` is the table's location in the config, for error messages.
+    fn apply(&mut self, table: &Table, path: &str, errors: &mut Vec<String>) {
+        match table.get("preset") {
+            Some(Value::String(name)) => match Keymap::from_preset(name) {
+                Some(preset) => {
+                    self.bindings.retain(|control, _| !control.is_key());
+                    self.bindings.extend(
+                        preset
+                            .bindings
+                            .into_iter()
+                            .filter(|(control, _)| control.is_key()),
+                    );
+                }
+                None => errors.push(format!(
+                    "{}preset must be one of {}",
+                    path,
+                    PRESET_NAMES.join(", ")
+                )),
+            },
+            Some(_) => errors.push(format!("{}preset must be a string", path)),
+            None => {}
+        }
+
+        self.bind(table, "keys", path, key_from_name, errors);
+        self.bind(table, "gamepad", path, gamepad_control_from_name, errors);
+    }
+
+    /// Applies the bindings in `table[field]`, each from a CHIP-8 key to the
+    /// name of a control or a list of them, named as `control_from_name`
+    /// expects.
+    fn bind(
+        &mut self,
+        table: &Table,
+        field: &str,
+        path: &str,
+        control_from_name: fn(&str) -> Option<Control>,
+        errors: &mut Vec<String>,
+    ) {
+        let controls = match table.get(field) {
+            Some(Value::Table(controls)) => controls,
+            Some(_) => return errors.push(format!("{}{} must be a table", path, field)),
+            None => return,
+        };
+        let mut bound = HashMap::new();
+        for (chip8_key, names) in controls {
+            let location = format!("{}{}.{}", path, field, chip8_key);
+            let key_code = match usize::from_str_radix(chip8_key, 16) {
+                Ok(key_code) if key_code < 16 => key_code,
+                _ => {
+                    errors.push(format!("{}: CHIP-8 keys are 0 to F", location));
+                    continue;
+                }
+            };
+            let names = match names {
+                Value::String(name) => vec![name.as_str()],
+                Value::Array(names) if names.iter().all(Value::is_str) => {
+                    names.iter().filter_map(Value::as_str).collect()
+                }
+                _ => {
+                    errors.push(format!("{} must be a name or a list of names", location));
+                    continue;
+                }
+            };
+
+            let is_key = field == "keys";
+            let noun = if is_key { "key" } else { "control" };
+            self.bindings.retain(|control, bound_code| {
+                *bound_code != key_code || control.is_key() != is_key
+            });
+            for name in names {
+                let control = match control_from_name(name) {
+                    Some(control) => control,
+                    None => {
+                        errors.push(format!("{}: Unknown {} name {}", location, noun, name));
+                        continue;
+                    }
+                };
+                if let Some(other) = bound.insert(control, chip8_key) {
+                    if other != chip8_key {
+                        errors.push(format!(
+                            "{}: {} is already bound to {}",
+                            location, name, other
+                        ));
+                    }
+                }
+                self.bindings.insert(control, key_code);
+            }
+        }
+    }
+}
+
+/// Returns the cardinal directions a d-pad state holds.
+fn hat_directions(state: HatState) -> impl Iterator<Item = HatState> {
+    let directions: &[HatState] = match state {
+        HatState::Centered => &[],
+        HatState::Up => &[HatState::Up],
+        HatState::Right => &[HatState::Right],
+        HatState::Down => &[HatState::Down],
+        HatState::Left => &[HatState::Left],
+        HatState::RightUp => &[HatState::Right, HatState::Up],
+        HatState::RightDown => &[HatState::Right, HatState::Down],
+        HatState::LeftUp => &[HatState::Left, HatState::Up],
+        HatState::LeftDown => &[HatState::Left, HatState::Down],
+    };
+    directions.iter().cloned()
+}
+
+/// Looks up a key by the name of its `Key` variant, ignoring case.
+fn key_from_name(name: &str) -> Option<Control> {
+    let name = name.to_lowercase();
+    let name = if name.len() == 1 && name.as_bytes()[0].is_ascii_digit() {
+        format!("d{}", name)
+    } else {
+        name
+    };
+
+    // Key codes follow SDL: printable keys are their ASCII code, and the
+    // rest start at 0x40000039 (CapsLock).
+    (0..0x80)
+        .chain(0x4000_0039..=0x4000_011a)
+        .map(Key::from)
+        .filter(|key| *key != Key::Unknown)
+        .find(|key| format!("{:?}", key).to_lowercase() == name)
+        .map(Control::Key)
+}
+
+/// Looks up a controller's d-pad direction, button or axis direction by name,
+/// ignoring case.
+fn gamepad_control_from_name(name: &str) -> Option<Control> {
+    let name = name.to_lowercase();
+    let control = match name.as_str() {
+        "up" => Control::Hat(HatState::Up),
+        "down" => Control::Hat(HatState::Down),
+        "left" => Control::Hat(HatState::Left),
+        "right" => Control::Hat(HatState::Right),
+        _ if name.starts_with("button") => Control::GamepadButton(name[6..].parse().ok()?),
+        _ if name.starts_with("axis") && name.ends_with('+') => {
+            Control::Axis(name[4..name.len() - 1].parse().ok()?, true)
+        }
+        _ if name.starts_with("axis") && name.ends_with('-') => {
+            Control::Axis(name[4..name.len() - 1].parse().ok()?, false)
+        }
+        _ => return None,
+    };
+    Some(control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use chip8::cpu::user_interface::HeadlessUI;
+    use piston_window::{ButtonArgs, ButtonState, Event, Input};
+    use std::path::Path;
+
+    fn key_event(key: Key, state: ButtonState) -> Event {
+        Event::Input(Input::Button(ButtonArgs {
+            state,
+            button: Button::Keyboard(key),
+            scancode: None,
+        }))
+    }
+
+    fn pressed_keys(ui: &HeadlessUI) -> Vec<usize> {
+        (0..16).filter(|key| ui.is_key_pressed(*key)).collect()
+    }
+
+    /// Returns the CHIP-8 key that `key` presses on its own, if any.
+    fn key_for(keymap: &mut Keymap, key: Key) -> Option<usize> {
+        let mut ui = HeadlessUI::new();
+        keymap.handle_event(&key_event(key, ButtonState::Press), &mut ui);
+        let pressed = pressed_keys(&ui);
+        keymap.handle_event(&key_event(key, ButtonState::Release), &mut ui);
+        pressed.first().cloned()
+    }
+
+    fn parse(config: &str) -> Result<Keymap, String> {
+        Config::parse(config, Path::new("roms/INVADERS.ch8"), &[]).map(|config| config.keymap)
+    }
+
+    #[test]
+    fn defaults_to_qwerty() {
+        let mut keymap = parse("").unwrap();
+        assert_eq!(key_for(&mut keymap, Key::D1), Some(0x1));
+        assert_eq!(key_for(&mut keymap, Key::Q), Some(0x4));
+        assert_eq!(key_for(&mut keymap, Key::V), Some(0xF));
+        assert_eq!(key_for(&mut keymap, Key::Space), None);
+    }
+
+    #[test]
+    fn picks_a_preset() {
+        let mut keymap = parse("preset = \"azerty\"").unwrap();
+        assert_eq!(key_for(&mut keymap, Key::A), Some(0x4));
+        assert_eq!(key_for(&mut keymap, Key::Q), Some(0x7));
+        assert_eq!(key_for(&mut keymap, Key::W), Some(0xA));
+    }
+
+    #[test]
+    fn keys_replace_the_bindings_of_a_chip8_key() {
+        let mut keymap = parse("[keys]\n5 = [\"Z\", \"up\"]\na = \"0\"").unwrap();
+        assert_eq!(key_for(&mut keymap, Key::Z), Some(0x5));
+        assert_eq!(key_for(&mut keymap, Key::Up), Some(0x5));
+        assert_eq!(key_for(&mut keymap, Key::W), None);
+        assert_eq!(key_for(&mut keymap, Key::D0), Some(0xA));
+    }
+
+    #[test]
+    fn overrides_apply_only_to_their_rom() {
+        let config = "[roms.INVADERS]\npreset = \"dvorak\"\nkeys = { 4 = \"Left\" }\n\
+                      [roms.BRIX]\nkeys = { 4 = \"Right\" }";
+        let mut keymap = parse(config).unwrap();
+        assert_eq!(key_for(&mut keymap, Key::Left), Some(0x4));
+        assert_eq!(key_for(&mut keymap, Key::Right), None);
+        assert_eq!(key_for(&mut keymap, Key::O), Some(0x8));
+    }
+
+    #[test]
+    fn key_stays_pressed_while_any_binding_is_held() {
+        let mut keymap = parse("[keys]\n5 = [\"W\", \"Up\"]").unwrap();
+        let mut ui = HeadlessUI::new();
+        keymap.handle_event(&key_event(Key::W, ButtonState::Press), &mut ui);
+        keymap.handle_event(&key_event(Key::Up, ButtonState::Press), &mut ui);
+        keymap.handle_event(&key_event(Key::W, ButtonState::Release), &mut ui);
+        assert_eq!(pressed_keys(&ui), [0x5]);
+        keymap.handle_event(&key_event(Key::Up, ButtonState::Release), &mut ui);
+        assert!(pressed_keys(&ui).is_empty());
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let config = "preset = \"colemak\"\nspeed = 10\n[keys]\n5 = \"Nope\"\ng = \"W\"\n\
+                      [roms.BRIX]\nkeys = { 4 = 4 }";
+        let errors = parse(config).err().unwrap();
+        let errors: Vec<&str> = errors.lines().collect();
+        assert_eq!(
+            errors,
+            [
+                "Unknown setting speed",
+                "preset must be one of qwerty, azerty, dvorak",
+                "keys.5: Unknown key name Nope",
+                "keys.g: CHIP-8 keys are 0 to F",
+                "roms.BRIX.keys.4 must be a name or a list of names",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_a_key_bound_twice() {
+        let errors = parse("[keys]\n4 = \"Left\"\n6 = \"Left\"").err().unwrap();
+        assert_eq!(errors, "keys.6: Left is already bound to 4");
+    }
+
+    #[test]
+    fn gamepad_bindings_leave_the_keyboard_alone() {
+        let config = "[gamepad]\n5 = [\"Button1\", \"Axis2+\"]\n[keys]\n5 = \"Space\"";
+        let mut keymap = parse(config).unwrap();
+        assert_eq!(key_for(&mut keymap, Key::Space), Some(0x5));
+        assert_eq!(key_for(&mut keymap, Key::W), None);
+        assert!(!keymap.bindings.contains_key(&Control::GamepadButton(0)));
+        assert_eq!(keymap.bindings.get(&Control::GamepadButton(1)), Some(&0x5));
+        assert_eq!(keymap.bindings.get(&Control::Axis(2, true)), Some(&0x5));
+        assert_eq!(keymap.bindings.get(&Control::Hat(HatState::Up)), Some(&0x2));
+    }
+
+    #[test]
+    fn rejects_unknown_control_names() {
+        let errors = parse("[gamepad]\n5 = [\"Button1\", \"Axis2\", \"Trigger\"]")
+            .err()
+            .unwrap();
+        let errors: Vec<&str> = errors.lines().collect();
+        assert_eq!(
+            errors,
+            [
+                "gamepad.5: Unknown control name Axis2",
+                "gamepad.5: Unknown control name Trigger",
+            ]
+        );
+    }
+
+    #[test]
+    fn rom_keys_only_bind_what_the_config_leaves_unbound() {
+        let rom_keys = [(0x4, "Left".to_string()), (0x6, "Right".to_string())];
+        let config = "[keys]\n6 = \"Right\"\n1 = \"Left\"";
+        let mut keymap = Config::parse(config, Path::new("roms/INVADERS.ch8"), &rom_keys)
+            .unwrap()
+            .keymap;
+        assert_eq!(key_for(&mut keymap, Key::Left), Some(0x1));
+        assert_eq!(key_for(&mut keymap, Key::Right), Some(0x6));
+        assert_eq!(key_for(&mut keymap, Key::Q), Some(0x4));
+    }
+}
@@ -0,0 +1,17 @@
+pub mod assembler;
+
+pub mod clock;
+
+pub mod cpu;
+
+pub mod debugger;
+
+pub mod disassembler;
+
+pub mod movie;
+
+pub mod rewind;
+
+pub mod rom;
+
+pub mod trace;
@@ -1,96 +1,674 @@
+use piston_window::texture::{CreateTexture, Format, UpdateTexture};
 use piston_window::*;
-use std::collections::HashMap;
 use std::env;
+use std::fs::File;
 use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
 
-mod cpu;
-use cpu::user_interface::{PistonUI, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use chip8::clock::Clock;
+use chip8::cpu::{self, CpuFault, RomHash, Snapshot, StepOutcome};
+use chip8::cpu::audio::{AudioSink, NullSink, RodioSink, WavSink};
+use chip8::cpu::random::SeededRandom;
+use chip8::cpu::user_interface::{
+    KeyPad, PistonUI, Region, Screen, LORES_HEIGHT, LORES_WIDTH, UI,
+};
+use chip8::debugger::{self, Debugger};
+use chip8::movie::{Movie, MovieError, MovieRecorder};
+use chip8::rewind::RewindBuffer;
+use chip8::rom::Rom;
+use chip8::trace::Tracer;
+
+mod config;
+mod gamepad;
+mod keymap;
+mod options;
+mod palette;
+mod phosphor;
+use config::Config;
+use gamepad::Gamepads;
+use options::Options;
+use palette::Palette;
+use phosphor::Phosphor;
+
+const WINDOW_TITLE: &str = "CHIP-8 Interpreter";
+const WINDOW_WIDTH: u32 = 640;
+const WINDOW_HEIGHT: u32 = 320;
+
+const SAVE_STATE_SLOTS: usize = 10;
+
+/// The smallest scale at which pixels are drawn with a gap between them.
+const GRID_MIN_SCALE: f64 = 3.0;
+/// Drawn over the lower half of every row of pixels.
+const SCANLINE_COLOR: [f32; 4] = [0.0, 0.0, 0.0, 0.4];
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("usage: chip8.exe <program_path>");
-        std::process::exit(1);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            println!("{}", message);
+            println!("{}", options::USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let rom = match Rom::load(Path::new(&options.rom_path)) {
+        Ok(rom) => rom,
+        Err(error) => {
+            println!("Failed to load {}: {}", options.rom_path, error);
+            std::process::exit(1);
+        }
+    };
+    let rom_info = rom.info();
+    let mut title = WINDOW_TITLE.to_string();
+    if let Some(info) = &rom_info {
+        match &info.author {
+            Some(author) => println!("{} by {}", info.title, author),
+            None => println!("{}", info.title),
+        }
+        title = format!("{} - {}", WINDOW_TITLE, info.title);
+        options.apply_rom_info(info);
     }
 
-    let rom_contents = std::fs::read(&args[1])?;
-
-    let mut keypad_map = HashMap::new();
-    keypad_map.insert(Button::Keyboard(Key::D1), 1);
-    keypad_map.insert(Button::Keyboard(Key::D2), 2);
-    keypad_map.insert(Button::Keyboard(Key::D3), 3);
-    keypad_map.insert(Button::Keyboard(Key::D4), 0xC);
-    keypad_map.insert(Button::Keyboard(Key::Q), 4);
-    keypad_map.insert(Button::Keyboard(Key::W), 5);
-    keypad_map.insert(Button::Keyboard(Key::E), 6);
-    keypad_map.insert(Button::Keyboard(Key::R), 0xD);
-    keypad_map.insert(Button::Keyboard(Key::A), 7);
-    keypad_map.insert(Button::Keyboard(Key::S), 8);
-    keypad_map.insert(Button::Keyboard(Key::D), 9);
-    keypad_map.insert(Button::Keyboard(Key::F), 0xE);
-    keypad_map.insert(Button::Keyboard(Key::Z), 0xA);
-    keypad_map.insert(Button::Keyboard(Key::X), 0);
-    keypad_map.insert(Button::Keyboard(Key::C), 0xB);
-    keypad_map.insert(Button::Keyboard(Key::V), 0xF);
-
-    let display = Arc::new(Mutex::new([[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT]));
-    let cpu_thread_display = Arc::clone(&display);
+    let rom_keys = rom_info.as_ref().map_or(&[][..], |info| &info.keys[..]);
+    let Config {
+        mut keymap,
+        palette,
+    } = match load_config(&options, rom_keys) {
+        Ok(config) => config,
+        Err(message) => {
+            println!("{}", message);
+            std::process::exit(1);
+        }
+    };
+    let palette = options.palette.unwrap_or(palette);
+
+    let frame = Arc::new(Mutex::new(Screen::new(LORES_WIDTH, LORES_HEIGHT)));
     let keypad = Arc::new(Mutex::new([false; 16]));
-    let cpu_thread_keypad = Arc::clone(&keypad);
-    let mut ui = PistonUI {
-        display: display,
-        keypad: keypad,
+    let input = match start_movie(&mut options, rom.hash, &keypad) {
+        Ok(input) => input,
+        Err(message) => {
+            println!("{}", message);
+            std::process::exit(1);
+        }
+    };
+    if let Err(error) = rom.check_fits(options.platform) {
+        println!("Failed to load {}: {}", options.rom_path, error);
+        std::process::exit(1);
+    }
+    // While a movie is recorded or replayed, the keys pressed in the window
+    // only reach the CPU when the movie latches them at the start of a frame.
+    let cpu_thread_keypad = match input {
+        Input::Live => Arc::clone(&keypad),
+        _ => Arc::new(Mutex::new([false; 16])),
     };
-    let cpu_thread_ui = PistonUI {
-        display: cpu_thread_display,
-        keypad: cpu_thread_keypad,
+    let outputs = match open_audio(&options).and_then(|audio| {
+        let tracer = open_trace(&options)?;
+        Ok(Outputs { audio, tracer })
+    }) {
+        Ok(outputs) => outputs,
+        Err(message) => {
+            println!("{}", message);
+            std::process::exit(1);
+        }
+    };
+    let cpu_thread_ui = PistonUI::new(Arc::clone(&frame), cpu_thread_keypad);
+    let mut ui = PistonUI::new(frame, keypad);
+
+    let (debugger, debugger_commands) = if options.debug {
+        let (command_sender, command_receiver) = mpsc::channel();
+        let repl_command_sender = command_sender.clone();
+        thread::spawn(move || run_debugger_repl(repl_command_sender));
+        (Some(Debugger::new(command_receiver)), Some(command_sender))
+    } else {
+        (None, None)
     };
 
-    thread::spawn(move || {
-        let mut cpu = cpu::Cpu::new(rom_contents, cpu_thread_ui);
-        loop {
-            cpu.execute();
+    let rom_path = options.rom_path.clone();
+    let (grid, scanlines) = (options.grid, options.scanlines);
+    let mut phosphor = if options.fade_frames > 0 || options.blend {
+        Some(Phosphor::new(options.fade_frames, options.blend))
+    } else {
+        None
+    };
+    let mut save_state_slot = 0;
+    let (machine_command_sender, machine_command_receiver) = mpsc::channel();
+    let (fault_sender, fault_receiver) = mpsc::channel();
+    let cpu_thread = thread::spawn(move || {
+        if let Err(fault) = run_cpu(
+            rom.bytes,
+            cpu_thread_ui,
+            outputs,
+            &options,
+            debugger,
+            machine_command_receiver,
+            input,
+        ) {
+            println!("{}", fault);
+            let _ = fault_sender.send(fault);
         }
     });
 
-    let mut window: PistonWindow = WindowSettings::new(
-        "CHIP-8 Interpreter",
-        [DISPLAY_WIDTH as u32 * 10, DISPLAY_HEIGHT as u32 * 10],
-    )
-    .build()
-    .unwrap();
+    let mut window: PistonWindow =
+        WindowSettings::new(title.as_str(), [WINDOW_WIDTH, WINDOW_HEIGHT])
+            .build()
+            .unwrap();
+    let mut texture = None;
+    let mut fullscreen = false;
+    let mut gamepads = match Gamepads::open() {
+        Ok(gamepads) => Some(gamepads),
+        Err(error) => {
+            println!("Failed to open controllers, they are disabled: {}", error);
+            None
+        }
+    };
     while let Some(e) = window.next() {
+        if let Ok(fault) = fault_receiver.try_recv() {
+            window.set_title(format!("{} - {}", title, fault));
+        }
+
+        if e.render_args().is_some() {
+            update_texture(&mut window, &mut texture, &ui, &palette, &mut phosphor);
+        }
         window.draw_2d(&e, |c, g| {
-            clear([0.0, 0.0, 0.0, 1.0], g);
-            for (j, line) in ui.get_display().iter().enumerate() {
-                for (i, pixel) in line.iter().enumerate() {
-                    if *pixel {
-                        rectangle(
-                            [1.0, 1.0, 1.0, 1.0],           // red
-                            [i as f64, j as f64, 1.0, 1.0], // rectangle
-                            c.zoom(10.0).transform,
-                            g,
-                        );
-                    }
-                }
+            clear(palette.0[0], g);
+            if let Some(texture) = &texture {
+                draw_display(texture, &palette, grid, scanlines, c, g);
             }
         });
 
+        keymap.handle_event(&e, &mut ui);
+        if let (Some(gamepads), Some(_)) = (&mut gamepads, e.update_args()) {
+            for event in gamepads.poll() {
+                keymap.handle_event(&event, &mut ui);
+            }
+        }
+
         if let Some(button) = e.press_args() {
-            if let Some(key_code) = keypad_map.get(&button) {
-                ui.set_key_pressed(*key_code, true);
+            let state_path = || PathBuf::from(format!("{}.state{}", rom_path, save_state_slot));
+            let command = match button {
+                Button::Keyboard(Key::Backspace) => Some(MachineCommand::Rewind(true)),
+                Button::Keyboard(Key::F5) => Some(MachineCommand::SaveState(state_path())),
+                Button::Keyboard(Key::F9) => Some(MachineCommand::LoadState(state_path())),
+                _ => None,
+            };
+            if let Some(command) = command {
+                let _ = machine_command_sender.send(command);
+            }
+
+            let slot_change = match button {
+                Button::Keyboard(Key::F6) => SAVE_STATE_SLOTS - 1,
+                Button::Keyboard(Key::F7) => 1,
+                _ => 0,
+            };
+            if slot_change != 0 {
+                save_state_slot = (save_state_slot + slot_change) % SAVE_STATE_SLOTS;
+                println!("Save state slot {}", save_state_slot);
+            }
+
+            if button == Button::Keyboard(Key::F11) {
+                fullscreen = !fullscreen;
+                let gl_window = &window.window.window;
+                let monitor = if fullscreen {
+                    Some(gl_window.get_current_monitor())
+                } else {
+                    None
+                };
+                gl_window.set_fullscreen(monitor);
             }
         }
 
         if let Some(button) = e.release_args() {
-            if let Some(key_code) = keypad_map.get(&button) {
-                ui.set_key_pressed(*key_code, false);
+            if button == Button::Keyboard(Key::Backspace) {
+                let _ = machine_command_sender.send(MachineCommand::Rewind(false));
             }
         }
     }
 
+    // Let the CPU thread finish writing its output files, resuming it first
+    // if the debugger has it paused.
+    let _ = machine_command_sender.send(MachineCommand::Quit);
+    if let Some(debugger_commands) = debugger_commands {
+        let _ = debugger_commands.send(debugger::Command::Continue);
+    }
+    let _ = cpu_thread.join();
+
     Ok(())
 }
+
+/// Uploads the part of the published frame that changed since the last
+/// render, recreating the texture when the resolution changes. With a
+/// phosphor, the frame is shown through it, and fading pixels change too.
+fn update_texture(
+    window: &mut PistonWindow,
+    texture: &mut Option<G2dTexture>,
+    ui: &PistonUI,
+    palette: &Palette,
+    phosphor: &mut Option<Phosphor>,
+) {
+    let mut frame = ui.get_display();
+    let dirty_region = frame.take_dirty_region();
+    let region = match phosphor {
+        Some(phosphor) => phosphor.update(&frame),
+        None => dirty_region,
+    };
+    let resolution = (frame.width() as u32, frame.height() as u32);
+    let recreate = match texture {
+        Some(texture) => texture.get_size() != resolution,
+        None => true,
+    };
+    let region = match region {
+        _ if recreate => Region {
+            x: 0,
+            y: 0,
+            width: frame.width(),
+            height: frame.height(),
+        },
+        Some(region) => region,
+        None => return,
+    };
+
+    let mut pixels = Vec::with_capacity(region.width * region.height * 4);
+    for y in region.y..region.y + region.height {
+        for x in region.x..region.x + region.width {
+            let color = match phosphor {
+                Some(phosphor) => {
+                    let (planes, intensity) = phosphor.pixel(x, y);
+                    palette::mix(palette.0[0], palette.0[planes as usize], intensity)
+                }
+                None => palette.0[frame.get(x, y) as usize],
+            };
+            pixels.extend(color.iter().map(|channel| (channel * 255.0) as u8));
+        }
+    }
+    drop(frame);
+
+    let offset = [region.x as u32, region.y as u32];
+    let size = [region.width as u32, region.height as u32];
+    match texture {
+        Some(texture) if !recreate => {
+            UpdateTexture::update(texture, &mut window.encoder, Format::Rgba8, &pixels, offset, size)
+                .unwrap();
+        }
+        _ => {
+            let settings = TextureSettings::new().filter(Filter::Nearest);
+            *texture = Some(
+                CreateTexture::create(&mut window.factory, Format::Rgba8, &pixels, size, &settings)
+                    .unwrap(),
+            );
+        }
+    }
+}
+
+/// Draws the display as large as the window allows while keeping its aspect
+/// ratio, centered, and at a whole scale unless the window is smaller than the
+/// display.
+fn draw_display(
+    texture: &G2dTexture,
+    palette: &Palette,
+    grid: bool,
+    scanlines: bool,
+    c: Context,
+    g: &mut G2d,
+) {
+    let [view_width, view_height] = c.get_view_size();
+    let width = f64::from(texture.get_width());
+    let height = f64::from(texture.get_height());
+    let mut scale = (view_width / width).min(view_height / height);
+    if scale >= 1.0 {
+        scale = scale.floor();
+    }
+    let x = ((view_width - width * scale) / 2.0).floor();
+    let y = ((view_height - height * scale) / 2.0).floor();
+    image(texture, c.transform.trans(x, y).zoom(scale), g);
+
+    if grid && scale >= GRID_MIN_SCALE {
+        let gap = (scale / 8.0).floor().max(1.0);
+        for column in 1..=texture.get_width() {
+            let left = x + f64::from(column) * scale - gap;
+            rectangle(palette.0[0], [left, y, gap, height * scale], c.transform, g);
+        }
+        for row in 1..=texture.get_height() {
+            let top = y + f64::from(row) * scale - gap;
+            rectangle(palette.0[0], [x, top, width * scale, gap], c.transform, g);
+        }
+    }
+    if scanlines && scale >= 2.0 {
+        for row in 0..texture.get_height() {
+            let top = y + (f64::from(row) + 0.5) * scale;
+            let line = [x, top, width * scale, scale / 2.0];
+            rectangle(SCANLINE_COLOR, line, c.transform, g);
+        }
+    }
+}
+
+/// Loads the config file given with `--config`, or the default one if it
+/// exists, falling back to the default settings.
+fn load_config(options: &Options, rom_keys: &[(usize, String)]) -> Result<Config, String> {
+    let config_path = match &options.config_path {
+        Some(path) => Path::new(path),
+        None if Path::new(config::DEFAULT_CONFIG_PATH).exists() => {
+            Path::new(config::DEFAULT_CONFIG_PATH)
+        }
+        None => return Config::parse("", Path::new(&options.rom_path), rom_keys),
+    };
+    Config::load(config_path, Path::new(&options.rom_path), rom_keys)
+}
+
+/// Requests from the window to the CPU thread, handled between instructions.
+enum MachineCommand {
+    SaveState(PathBuf),
+    LoadState(PathBuf),
+    /// Starts or stops stepping back through the rewind buffer, one frame per
+    /// frame.
+    Rewind(bool),
+    /// Stops the CPU thread, as the window was closed.
+    Quit,
+}
+
+/// Where the CPU thread writes what the program produces besides the display.
+/// They are opened on the main thread, which exits if one of them can't be.
+struct Outputs {
+    audio: Box<dyn AudioSink + Send>,
+    tracer: Option<Tracer>,
+}
+
+/// Where the CPU's keypad input comes from.
+enum Input {
+    /// The window's key events, as they happen.
+    Live,
+    /// The window's keypad, latched once per frame and recorded.
+    Recording(MovieRecorder, Arc<Mutex<KeyPad>>),
+    /// A movie, followed by the window's keypad once the movie ends.
+    Replaying(Movie, Arc<Mutex<KeyPad>>),
+}
+
+impl Input {
+    fn is_live(&self) -> bool {
+        matches!(self, Input::Live)
+    }
+
+    /// Sets the CPU's keypad for the frame about to start.
+    fn latch_frame<T: UI>(&mut self, frame: usize, cpu: &mut cpu::Cpu<T>) {
+        let window_keypad = match self {
+            Input::Live => return,
+            Input::Recording(recorder, window_keypad) => {
+                let keypad = *window_keypad.lock().unwrap();
+                if let Err(error) = recorder.record(&keypad) {
+                    println!("Failed to record frame {}: {}", frame, error);
+                }
+                keypad
+            }
+            Input::Replaying(movie, window_keypad) => {
+                if movie.play_frame(frame, cpu.ui_mut()) {
+                    return;
+                }
+                if frame == movie.len() {
+                    println!("Movie ended after {} frames", frame);
+                }
+                *window_keypad.lock().unwrap()
+            }
+        };
+
+        for (key_code, pressed) in window_keypad.iter().enumerate() {
+            cpu.ui_mut().set_key_pressed(key_code, *pressed);
+        }
+    }
+}
+
+/// Opens the movie to record or replay, if any. A replayed movie overrides the
+/// options the run depends on.
+fn start_movie(
+    options: &mut Options,
+    rom_hash: RomHash,
+    window_keypad: &Arc<Mutex<KeyPad>>,
+) -> Result<Input, String> {
+    if let Some(path) = &options.replay_path {
+        let movie = File::open(path)
+            .map_err(MovieError::from)
+            .and_then(|mut file| Movie::read_from(&mut file))
+            .map_err(|error| format!("Failed to load movie {}: {}", path, error))?;
+        if movie.rom_hash != rom_hash {
+            return Err(format!("The movie {} is for a different ROM", path));
+        }
+
+        options.platform = movie.platform;
+        options.quirks = movie.quirks;
+        options.sys_mode = movie.sys_mode;
+        options.clock_rate = movie.clock_rate;
+        options.seed = movie.seed;
+        return Ok(Input::Replaying(movie, Arc::clone(window_keypad)));
+    }
+
+    if let Some(path) = &options.record_path {
+        let movie = Movie::new(
+            rom_hash,
+            options.platform,
+            options.quirks,
+            options.sys_mode,
+            options.clock_rate,
+            options.seed,
+        );
+        let recorder = File::create(path)
+            .and_then(|file| MovieRecorder::new(&movie, Box::new(file)))
+            .map_err(|error| format!("Failed to record movie {}: {}", path, error))?;
+        return Ok(Input::Recording(recorder, Arc::clone(window_keypad)));
+    }
+
+    Ok(Input::Live)
+}
+
+/// Runs the program until it exits or faults. The window stays open either
+/// way, showing the last frame.
+fn run_cpu(
+    rom: Vec<u8>,
+    ui: PistonUI,
+    outputs: Outputs,
+    options: &Options,
+    mut debugger: Option<Debugger>,
+    machine_commands: mpsc::Receiver<MachineCommand>,
+    mut input: Input,
+) -> Result<(), CpuFault> {
+    let Outputs { audio, mut tracer } = outputs;
+    let mut cpu = cpu::Cpu::new(
+        rom,
+        ui,
+        options.platform,
+        options.quirks,
+        options.timer_mode,
+        audio,
+        Box::new(SeededRandom::new(options.seed)),
+    )?;
+    cpu.set_sys_mode(options.sys_mode);
+    let mut clock = Clock::new(options.clock_rate);
+    let rewind_frames = options.rewind_seconds.checked_mul(cpu::TIMER_RATE);
+    let mut rewind = RewindBuffer::new(rewind_frames.unwrap_or(u32::MAX) as usize);
+    let mut rewinding = false;
+    let mut frame = 0;
+    input.latch_frame(frame, &mut cpu);
+    loop {
+        for command in machine_commands.try_iter() {
+            match command {
+                MachineCommand::SaveState(path) => save_state(&cpu, &path),
+                MachineCommand::LoadState(_) | MachineCommand::Rewind(true)
+                    if !input.is_live() =>
+                {
+                    println!("Can't load states or rewind during a movie");
+                }
+                MachineCommand::LoadState(path) => {
+                    load_state(&mut cpu, &path);
+                    cpu.ui_mut().present();
+                }
+                MachineCommand::Rewind(held) => rewinding = held,
+                MachineCommand::Quit => return Ok(()),
+            }
+        }
+
+        if rewinding {
+            clock.wait();
+            if clock.frame_elapsed() {
+                if let Some(snapshot) = rewind.pop() {
+                    match cpu.restore(&snapshot) {
+                        Ok(()) => cpu.ui_mut().present(),
+                        Err(error) => println!("Failed to rewind: {}", error),
+                    }
+                }
+            }
+            continue;
+        }
+
+        if let Some(debugger) = &mut debugger {
+            debugger.before_step(&mut cpu, &mut rewind);
+        }
+
+        clock.wait();
+        // Fetched beforehand, as the instruction may overwrite itself.
+        let before = cpu.registers();
+        let opcode = cpu.memory().read_u16_at(before.program_counter).unwrap_or(0);
+        let outcome = match cpu.execute() {
+            Ok(outcome) => outcome,
+            Err(fault) => {
+                trace_step(&mut tracer, opcode, before, cpu.registers(), true);
+                cpu.ui_mut().present();
+                return Err(fault);
+            }
+        };
+        if outcome == StepOutcome::WaitingForKey {
+            if let Some(tracer) = &mut tracer {
+                tracer.skip();
+            }
+        } else {
+            trace_step(&mut tracer, opcode, before, cpu.registers(), false);
+        }
+
+        // Show every step while debugging, rather than every frame.
+        if let Some(debugger) = &mut debugger {
+            debugger.after_step(&mut cpu);
+            cpu.ui_mut().present();
+        }
+        if outcome == StepOutcome::Halted {
+            cpu.ui_mut().present();
+            println!("Program exited");
+            return Ok(());
+        }
+        if clock.frame_elapsed() {
+            cpu.end_frame();
+            if let Some(Err(error)) = tracer.as_mut().map(Tracer::flush) {
+                println!("Failed to write trace: {}", error);
+                tracer = None;
+            }
+            rewind.push(&cpu.snapshot());
+            frame += 1;
+            input.latch_frame(frame, &mut cpu);
+        }
+    }
+}
+
+fn open_trace(options: &Options) -> Result<Option<Tracer>, String> {
+    let path = match &options.trace_path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    File::create(path)
+        .and_then(|file| {
+            Tracer::new(
+                Box::new(BufWriter::new(file)),
+                options.platform,
+                options.trace_filter.clone(),
+            )
+        })
+        .map(Some)
+        .map_err(|error| format!("Failed to create {}: {}", path, error))
+}
+
+/// Records an executed instruction, or one that faulted. Stops tracing if the
+/// trace can't be written.
+fn trace_step(
+    tracer: &mut Option<Tracer>,
+    opcode: u16,
+    before: cpu::Registers,
+    after: cpu::Registers,
+    faulted: bool,
+) {
+    if let Some(active) = tracer {
+        let result = if faulted {
+            active.record_fault(opcode, before, after)
+        } else {
+            active.record(opcode, before, after)
+        };
+        if let Err(error) = result {
+            println!("Failed to write trace: {}", error);
+            *tracer = None;
+        }
+    }
+}
+
+fn open_audio(options: &Options) -> Result<Box<dyn AudioSink + Send>, String> {
+    if options.mute {
+        return Ok(Box::new(NullSink));
+    }
+    if let Some(path) = &options.wav_path {
+        return match WavSink::create(Path::new(path), options.tone) {
+            Ok(sink) => Ok(Box::new(sink)),
+            Err(error) => Err(format!("Failed to create {}: {}", path, error)),
+        };
+    }
+
+    match RodioSink::new(options.tone) {
+        Some(sink) => Ok(Box::new(sink)),
+        None => {
+            println!("No audio output device found, sound is disabled");
+            Ok(Box::new(NullSink))
+        }
+    }
+}
+
+fn save_state<T: UI>(cpu: &cpu::Cpu<T>, path: &Path) {
+    let result = File::create(path).and_then(|mut file| cpu.snapshot().write_to(&mut file));
+    match result {
+        Ok(()) => println!("Saved state to {}", path.display()),
+        Err(error) => println!("Failed to save state to {}: {}", path.display(), error),
+    }
+}
+
+fn load_state<T: UI>(cpu: &mut cpu::Cpu<T>, path: &Path) {
+    let result = File::open(path)
+        .map_err(cpu::SnapshotError::from)
+        .and_then(|mut file| Snapshot::read_from(&mut file))
+        .and_then(|snapshot| cpu.restore(&snapshot));
+    match result {
+        Ok(()) => println!("Loaded state from {}", path.display()),
+        Err(error) => println!("Failed to load state from {}: {}", path.display(), error),
+    }
+}
+
+fn run_debugger_repl(commands: mpsc::Sender<debugger::Command>) {
+    println!("Debugger started with the program paused");
+    println!("{}", debugger::HELP);
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match debugger::Command::parse(&line) {
+            Ok(command) => {
+                if commands.send(command).is_err() {
+                    break;
+                }
+            }
+            Err(message) => println!("{}", message),
+        }
+    }
+}
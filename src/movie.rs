@@ -0,0 +1,335 @@
+use crate::cpu::user_interface::{KeyPad, UI};
+use crate::cpu::{Platform, Quirks, RomHash, Stack, SysMode};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"C8MV";
+
+/// The version of the movie format. Bump it whenever the format changes.
+const VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    NotAMovie,
+    UnsupportedVersion(u16),
+    /// The movie is truncated, or holds an impossible value.
+    Corrupt,
+}
+
+impl From<io::Error> for MovieError {
+    fn from(error: io::Error) -> MovieError {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => MovieError::Corrupt,
+            _ => MovieError::Io(error),
+        }
+    }
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Io(error) => write!(f, "{}", error),
+            MovieError::NotAMovie => write!(f, "Not a movie"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported movie version {}", version)
+            }
+            MovieError::Corrupt => write!(f, "The movie is corrupt"),
+        }
+    }
+}
+
+/// A recording of the keypad state on every frame, along with everything
+/// else a run depends on, so that replaying it reproduces the run exactly.
+/// Runs are only reproducible with `TimerMode::Ticked`.
+pub struct Movie {
+    pub rom_hash: RomHash,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub sys_mode: SysMode,
+    pub clock_rate: u32,
+    /// The seed of the CPU's random number generator.
+    pub seed: u64,
+    frames: Vec<u16>,
+}
+
+impl Movie {
+    pub fn new(
+        rom_hash: RomHash,
+        platform: Platform,
+        quirks: Quirks,
+        sys_mode: SysMode,
+        clock_rate: u32,
+        seed: u64,
+    ) -> Movie {
+        Movie {
+            rom_hash,
+            platform,
+            quirks,
+            sys_mode,
+            clock_rate,
+            seed,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Returns the number of frames recorded.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Appends the keypad state of the next frame.
+    pub fn record(&mut self, keypad: &KeyPad) {
+        self.frames.push(keypad_mask(keypad));
+    }
+
+    /// Sets the keypad of `ui` to its recorded state in `frame`. Returns
+    /// false, leaving the keypad untouched, once the movie has ended.
+    pub fn play_frame(&self, frame: usize, ui: &mut dyn UI) -> bool {
+        let mask = match self.frames.get(frame) {
+            Some(mask) => *mask,
+            None => return false,
+        };
+
+        for key_code in 0..16 {
+            ui.set_key_pressed(key_code, mask & 1 << key_code != 0);
+        }
+        true
+    }
+
+    /// Writes the movie: a header of the magic bytes, the format version and
+    /// the run's settings, followed by one big-endian keypad bitmask per frame
+    /// up to the end of the file.
+    pub fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        self.write_header(writer)?;
+        for mask in &self.frames {
+            writer.write_u16::<BigEndian>(*mask)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_header(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_u16::<BigEndian>(VERSION)?;
+        writer.write_all(&self.rom_hash)?;
+        writer.write_u8(self.platform.id())?;
+        writer.write_u8(quirk_flags(self.quirks))?;
+        writer.write_u16::<BigEndian>(self.quirks.stack.id())?;
+        writer.write_u8(self.sys_mode.id())?;
+        writer.write_u32::<BigEndian>(self.clock_rate)?;
+        writer.write_u64::<BigEndian>(self.seed)
+    }
+
+    pub fn read_from(reader: &mut dyn Read) -> Result<Movie, MovieError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(MovieError::NotAMovie);
+        }
+        let version = reader.read_u16::<BigEndian>()?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let mut rom_hash = [0; 20];
+        reader.read_exact(&mut rom_hash)?;
+        let platform = Platform::from_id(reader.read_u8()?).ok_or(MovieError::Corrupt)?;
+        let flags = reader.read_u8()?;
+        let stack = Stack::from_id(reader.read_u16::<BigEndian>()?);
+        let quirks = quirks_from_flags(flags, stack);
+        let sys_mode = SysMode::from_id(reader.read_u8()?).ok_or(MovieError::Corrupt)?;
+        let clock_rate = reader.read_u32::<BigEndian>()?;
+        if clock_rate == 0 {
+            return Err(MovieError::Corrupt);
+        }
+        let seed = reader.read_u64::<BigEndian>()?;
+
+        let mut frame_data = Vec::new();
+        reader.read_to_end(&mut frame_data)?;
+        if frame_data.len() % 2 != 0 {
+            return Err(MovieError::Corrupt);
+        }
+        let frames = frame_data
+            .chunks(2)
+            .map(|mut mask| mask.read_u16::<BigEndian>().unwrap())
+            .collect();
+
+        Ok(Movie {
+            rom_hash,
+            platform,
+            quirks,
+            sys_mode,
+            clock_rate,
+            seed,
+            frames,
+        })
+    }
+}
+
+/// Writes a movie as it is recorded, one frame at a time, so that the
+/// recording survives the process being closed at any moment.
+pub struct MovieRecorder {
+    writer: Box<dyn Write + Send>,
+}
+
+impl MovieRecorder {
+    /// Starts recording a run with the settings of `movie`, whose frames are
+    /// ignored.
+    pub fn new(movie: &Movie, mut writer: Box<dyn Write + Send>) -> io::Result<MovieRecorder> {
+        movie.write_header(&mut writer)?;
+        writer.flush()?;
+        Ok(MovieRecorder { writer })
+    }
+
+    /// Appends the keypad state of the next frame.
+    pub fn record(&mut self, keypad: &KeyPad) -> io::Result<()> {
+        self.writer.write_u16::<BigEndian>(keypad_mask(keypad))?;
+        self.writer.flush()
+    }
+}
+
+fn keypad_mask(keypad: &KeyPad) -> u16 {
+    keypad
+        .iter()
+        .enumerate()
+        .filter(|(_, pressed)| **pressed)
+        .fold(0, |mask, (key_code, _)| mask | 1 << key_code)
+}
+
+fn quirk_flags(quirks: Quirks) -> u8 {
+    [
+        quirks.shift_uses_vy,
+        quirks.load_store_increments_index,
+        quirks.jump_uses_vx,
+        quirks.logic_resets_vf,
+        quirks.clip_sprites,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |flags, (bit, set)| flags | (*set as u8) << bit)
+}
+
+fn quirks_from_flags(flags: u8, stack: Stack) -> Quirks {
+    let flag = |bit: u8| flags & 1 << bit != 0;
+    Quirks {
+        shift_uses_vy: flag(0),
+        load_store_increments_index: flag(1),
+        jump_uses_vx: flag(2),
+        logic_resets_vf: flag(3),
+        clip_sprites: flag(4),
+        stack,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::audio::NullSink;
+    use crate::cpu::random::SeededRandom;
+    use crate::cpu::user_interface::HeadlessUI;
+    use crate::cpu::{hash_rom, Cpu, TimerMode};
+
+    const FRAMES: usize = 20;
+    const STEPS_PER_FRAME: usize = 12;
+
+    /// Rolls random numbers into V0, and counts in V2 while key 0 is held.
+    const ROM: [u8; 10] = [
+        0xc0, 0xff, // RND V0, 0xff
+        0xe1, 0x9e, // SKP V1
+        0x12, 0x00, // JMP 0x200
+        0x72, 0x01, // ADD V2, 1
+        0x12, 0x00, // JMP 0x200
+    ];
+
+    fn new_cpu(ui: HeadlessUI, seed: u64) -> Cpu<HeadlessUI> {
+        Cpu::new(
+            ROM.to_vec(),
+            ui,
+            Platform::Chip8,
+            Quirks::VIP,
+            TimerMode::Ticked,
+            Box::new(NullSink),
+            Box::new(SeededRandom::new(seed)),
+        )
+        .unwrap()
+    }
+
+    fn run_frame(cpu: &mut Cpu<HeadlessUI>) {
+        for _ in 0..STEPS_PER_FRAME {
+            cpu.execute().unwrap();
+        }
+        cpu.end_frame();
+    }
+
+    fn state(cpu: &Cpu<HeadlessUI>) -> Vec<u8> {
+        let mut state = Vec::new();
+        cpu.snapshot().write_to(&mut state).unwrap();
+        state
+    }
+
+    #[test]
+    fn replaying_a_recorded_movie_reproduces_the_run() {
+        let mut ui = HeadlessUI::new();
+        ui.script_key(3, 0, true);
+        ui.script_key(7, 0, false);
+        ui.script_key(12, 0, true);
+        let mut cpu = new_cpu(ui, 7);
+        let mut movie = Movie::new(
+            hash_rom(&ROM),
+            Platform::Chip8,
+            Quirks::VIP,
+            SysMode::Fault,
+            700,
+            7,
+        );
+        for _ in 0..FRAMES {
+            movie.record(&cpu.ui().keypad);
+            run_frame(&mut cpu);
+        }
+        assert_ne!(cpu.registers().gpr[2], 0);
+
+        let mut bytes = Vec::new();
+        movie.write_to(&mut bytes).unwrap();
+        let movie = Movie::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(movie.len(), FRAMES);
+        assert_eq!(movie.rom_hash, hash_rom(&ROM));
+        assert!(movie.platform == Platform::Chip8);
+        assert!(movie.quirks == Quirks::VIP);
+        assert!(movie.sys_mode == SysMode::Fault);
+        assert_eq!(movie.clock_rate, 700);
+
+        let mut replay = new_cpu(HeadlessUI::new(), movie.seed);
+        for frame in 0..movie.len() {
+            assert!(movie.play_frame(frame, replay.ui_mut()));
+            run_frame(&mut replay);
+        }
+        assert!(!movie.play_frame(FRAMES, replay.ui_mut()));
+        assert_eq!(state(&replay), state(&cpu));
+    }
+
+    #[test]
+    fn rejects_a_truncated_movie() {
+        let movie = Movie::new(
+            hash_rom(&ROM),
+            Platform::Chip8,
+            Quirks::VIP,
+            SysMode::Fault,
+            700,
+            7,
+        );
+        let mut bytes = Vec::new();
+        movie.write_to(&mut bytes).unwrap();
+        bytes.push(0);
+
+        match Movie::read_from(&mut bytes.as_slice()) {
+            Err(MovieError::Corrupt) => {}
+            _ => panic!("read a movie with half a frame"),
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use crate::palette::{Palette, PALETTE_NAMES};
+use chip8::clock::DEFAULT_CLOCK_RATE;
+use chip8::cpu::audio::{ToneSettings, Waveform};
+use chip8::cpu::quirks::PRESET_NAMES;
+use chip8::cpu::{Platform, Quirks, Stack, SysMode, TimerMode};
+use chip8::rewind::{DEFAULT_REWIND_SECONDS, MAX_REWIND_SECONDS};
+use chip8::rom::RomInfo;
+use chip8::trace::TraceFilter;
+
+pub const USAGE: &str = "usage: chip8.exe [--speed <instructions_per_second>] \
+                         [--timers <ticked|wallclock>] [--platform <chip8|schip|xochip>] \
+                         [--quirks <vip|chip48|schip|xochip|modern>] [--stack <memory|depth>] \
+                         [--sys <ignore|fault>] [--rewind <seconds>] \
+                         [--seed <number>] [--record <movie_path> | --replay <movie_path>] \
+                         [--config <config_path>] [--palette <mono|green|amber>] \
+                         [--fade <frames>] [--blend] [--grid] [--scanlines] \
+                         [--waveform <square|sine>] [--tone <hertz>] [--volume <percent>] \
+                         [--mute | --wav <wav_path>] [--trace <trace_path> \
+                         [--trace-range <start>-<end>] [--trace-class <nibble>,...] \
+                         [--trace-changes]] [--debug] <program_path>";
+
+pub struct Options {
+    pub rom_path: String,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub clock_rate: u32,
+    pub timer_mode: TimerMode,
+    /// What calls to machine code do.
+    pub sys_mode: SysMode,
+    pub rewind_seconds: u32,
+    /// The seed of the CPU's random number generator.
+    pub seed: u64,
+    pub record_path: Option<String>,
+    pub replay_path: Option<String>,
+    /// The config file, or `None` for the default one.
+    pub config_path: Option<String>,
+    /// Overrides the palette of the config file.
+    pub palette: Option<Palette>,
+    /// How many frames pixels take to fade out once unlit.
+    pub fade_frames: u32,
+    /// Whether pixels stay lit for a frame after they are unlit.
+    pub blend: bool,
+    /// Whether to leave a gap between pixels.
+    pub grid: bool,
+    pub scanlines: bool,
+    pub tone: ToneSettings,
+    pub mute: bool,
+    /// Where to write the sound instead of playing it.
+    pub wav_path: Option<String>,
+    /// Where to write a trace of the instructions executed.
+    pub trace_path: Option<String>,
+    pub trace_filter: TraceFilter,
+    pub debug: bool,
+    /// Whether the command line set the platform, quirks and speed, which the
+    /// ROM database then leaves alone.
+    platform_given: bool,
+    quirks_given: bool,
+    clock_rate_given: bool,
+    /// Overrides the stack of the quirks, whichever they end up being.
+    stack: Option<Stack>,
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut rom_path = None;
+        let mut platform = None;
+        let mut quirks = None;
+        let mut stack = None;
+        let mut clock_rate = None;
+        let mut timer_mode = TimerMode::Ticked;
+        let mut sys_mode = SysMode::Ignore;
+        let mut rewind_seconds = DEFAULT_REWIND_SECONDS;
+        let mut seed = None;
+        let mut record_path = None;
+        let mut replay_path = None;
+        let mut config_path = None;
+        let mut palette = None;
+        let mut fade_frames = 0;
+        let mut blend = false;
+        let mut grid = false;
+        let mut scanlines = false;
+        let mut tone = ToneSettings::default();
+        let mut mute = false;
+        let mut wav_path = None;
+        let mut trace_path = None;
+        let mut trace_filter = TraceFilter::default();
+        let mut debug = false;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--speed" => {
+                    let rate = parse_value(arg, args.next())?;
+                    if rate == 0 {
+                        return Err("--speed must be greater than 0".to_string());
+                    }
+                    clock_rate = Some(rate);
+                }
+                "--platform" => {
+                    let name = args.next().map(String::as_str).unwrap_or_default();
+                    platform = Some(Platform::from_name(name).ok_or_else(|| {
+                        "--platform must be one of chip8, schip or xochip".to_string()
+                    })?);
+                }
+                "--quirks" => {
+                    let preset = args.next().map(String::as_str).unwrap_or_default();
+                    quirks = Some(Quirks::from_preset(preset).ok_or_else(|| {
+                        format!("--quirks must be one of {}", PRESET_NAMES.join(", "))
+                    })?);
+                }
+                "--stack" => {
+                    let value = args.next();
+                    stack = Some(match value.map(String::as_str) {
+                        Some("memory") => Stack::Memory,
+                        _ => {
+                            let depth: u16 = parse_value(arg, value)?;
+                            if depth == 0 {
+                                return Err(
+                                    "--stack must be memory or a depth greater than 0".to_string()
+                                );
+                            }
+                            Stack::Dedicated(depth.into())
+                        }
+                    });
+                }
+                "--sys" => {
+                    sys_mode = match args.next().map(String::as_str) {
+                        Some("ignore") => SysMode::Ignore,
+                        Some("fault") => SysMode::Fault,
+                        _ => return Err("--sys must be either ignore or fault".to_string()),
+                    }
+                }
+                "--timers" => {
+                    timer_mode = match args.next().map(String::as_str) {
+                        Some("ticked") => TimerMode::Ticked,
+                        Some("wallclock") => TimerMode::WallClock,
+                        _ => return Err("--timers must be either ticked or wallclock".to_string()),
+                    }
+                }
+                "--rewind" => {
+                    rewind_seconds = parse_value(arg, args.next())?;
+                    if rewind_seconds > MAX_REWIND_SECONDS {
+                        return Err(format!("--rewind must be at most {}", MAX_REWIND_SECONDS));
+                    }
+                }
+                "--seed" => seed = Some(parse_value(arg, args.next())?),
+                "--record" => record_path = Some(parse_value(arg, args.next())?),
+                "--replay" => replay_path = Some(parse_value(arg, args.next())?),
+                "--config" => config_path = Some(parse_value(arg, args.next())?),
+                "--palette" => {
+                    let name = args.next().map(String::as_str).unwrap_or_default();
+                    palette = Some(Palette::from_preset(name).ok_or_else(|| {
+                        format!("--palette must be one of {}", PALETTE_NAMES.join(", "))
+                    })?);
+                }
+                "--fade" => fade_frames = parse_value(arg, args.next())?,
+                "--blend" => blend = true,
+                "--grid" => grid = true,
+                "--scanlines" => scanlines = true,
+                "--waveform" => {
+                    tone.waveform = match args.next().map(String::as_str) {
+                        Some("square") => Waveform::Square,
+                        Some("sine") => Waveform::Sine,
+                        _ => return Err("--waveform must be either square or sine".to_string()),
+                    }
+                }
+                "--tone" => {
+                    tone.frequency = parse_value(arg, args.next())?;
+                    if !tone.frequency.is_finite() || tone.frequency <= 0.0 {
+                        return Err("--tone must be greater than 0".to_string());
+                    }
+                }
+                "--volume" => {
+                    let volume: u8 = parse_value(arg, args.next())?;
+                    if volume > 100 {
+                        return Err("--volume must be at most 100".to_string());
+                    }
+                    tone.volume = f32::from(volume) / 100.0;
+                }
+                "--mute" => mute = true,
+                "--wav" => wav_path = Some(parse_value(arg, args.next())?),
+                "--trace" => trace_path = Some(parse_value(arg, args.next())?),
+                "--trace-range" => {
+                    let range = args.next().map(String::as_str).unwrap_or_default();
+                    let (start, end) = match range.find('-') {
+                        Some(dash) => (&range[..dash], &range[dash + 1..]),
+                        None => return Err("--trace-range must be <start>-<end>".to_string()),
+                    };
+                    trace_filter.addresses =
+                        Some(parse_address(arg, start)?..=parse_address(arg, end)?);
+                }
+                "--trace-class" => {
+                    let classes = args.next().map(String::as_str).unwrap_or_default();
+                    trace_filter.classes = Some(
+                        classes
+                            .split(',')
+                            .map(|class| match u8::from_str_radix(class, 16) {
+                                Ok(class) if class < 16 => Ok(class),
+                                _ => Err(format!("Invalid opcode class for {}: {}", arg, class)),
+                            })
+                            .collect::<Result<_, _>>()?,
+                    );
+                }
+                "--trace-changes" => trace_filter.changes_only = true,
+                "--debug" => debug = true,
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ if rom_path.is_none() => rom_path = Some(arg.clone()),
+                _ => return Err(format!("Unexpected argument {}", arg)),
+            }
+        }
+
+        if mute && wav_path.is_some() {
+            return Err("--mute and --wav can't be used together".to_string());
+        }
+        let filtered = trace_filter.addresses.is_some()
+            || trace_filter.classes.is_some()
+            || trace_filter.changes_only;
+        if filtered && trace_path.is_none() {
+            return Err(
+                "--trace-range, --trace-class and --trace-changes need --trace".to_string(),
+            );
+        }
+        if record_path.is_some() && replay_path.is_some() {
+            return Err("--record and --replay can't be used together".to_string());
+        }
+        if (record_path.is_some() || replay_path.is_some()) && timer_mode != TimerMode::Ticked {
+            return Err("Movies can only be recorded and replayed with ticked timers".to_string());
+        }
+
+        let platform_given = platform.is_some();
+        let platform = platform.unwrap_or(Platform::Chip8);
+        let quirks_given = quirks.is_some();
+        let mut quirks = quirks.unwrap_or_else(|| Quirks::for_platform(platform));
+        if let Some(stack) = stack {
+            quirks.stack = stack;
+        }
+        Ok(Options {
+            rom_path: rom_path.ok_or_else(|| "Missing program path".to_string())?,
+            platform,
+            quirks,
+            clock_rate: clock_rate.unwrap_or(DEFAULT_CLOCK_RATE),
+            platform_given,
+            quirks_given,
+            clock_rate_given: clock_rate.is_some(),
+            stack,
+            timer_mode,
+            sys_mode,
+            rewind_seconds,
+            seed: seed.unwrap_or_else(rand::random),
+            record_path,
+            replay_path,
+            config_path,
+            palette,
+            fade_frames,
+            blend,
+            grid,
+            scanlines,
+            tone,
+            mute,
+            wav_path,
+            trace_path,
+            trace_filter,
+            debug,
+        })
+    }
+
+    /// Fills in the settings the command line left out from the ROM's entry in
+    /// the metadata database.
+    pub fn apply_rom_info(&mut self, info: &RomInfo) {
+        if let (Some(platform), false) = (info.platform, self.platform_given) {
+            self.platform = platform;
+            if !self.quirks_given {
+                self.quirks = Quirks::for_platform(platform);
+            }
+        }
+        if let (Some(quirks), false) = (info.quirks, self.quirks_given) {
+            self.quirks = quirks;
+        }
+        if let Some(stack) = self.stack.or(info.stack) {
+            self.quirks.stack = stack;
+        }
+        if let (Some(clock_rate), false) = (info.clock_rate, self.clock_rate_given) {
+            self.clock_rate = clock_rate;
+        }
+    }
+}
+
+/// Parses a hexadecimal address, with or without a leading `0x`.
+fn parse_address(option: &str, address: &str) -> Result<usize, String> {
+    let digits = address.trim_start_matches("0x");
+    usize::from_str_radix(digits, 16)
+        .map_err(|_| format!("Invalid address for {}: {}", option, address))
+}
+
+fn parse_value<V: std::str::FromStr>(option: &str, value: Option<&String>) -> Result<V, String> {
+    let value = value.ok_or_else(|| format!("Missing value for {}", option))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", option, value))
+}
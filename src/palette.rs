@@ -0,0 +1,178 @@
+use toml::value::Value;
+
+pub const PALETTE_NAMES: [&str; 3] = ["mono", "green", "amber"];
+
+pub type Color = [f32; 4];
+
+/// The colours of the display, indexed by the bitmask of planes a pixel is
+/// lit in: the background, the first plane, the second plane, and both, the
+/// last two only used by XO-CHIP.
+#[derive(Clone, Copy)]
+pub struct Palette(pub [Color; 4]);
+
+impl Palette {
+    pub fn from_preset(name: &str) -> Option<Palette> {
+        let colors = match name {
+            "mono" => [0x00_0000, 0xff_ffff, 0xaa_aaaa, 0x55_5555],
+            "green" => [0x00_1a00, 0x33_ff66, 0x22_aa44, 0x11_5522],
+            "amber" => [0x1a_0f00, 0xff_b000, 0xaa_7500, 0x55_3b00],
+            _ => return None,
+        };
+        Some(Palette([
+            rgb(colors[0]),
+            rgb(colors[1]),
+            rgb(colors[2]),
+            rgb(colors[3]),
+        ]))
+    }
+
+    /// Reads the `palette` setting of the config file: either the name of a
+    /// preset, or a list of `"#rrggbb"` colours in the order of `Palette`.
+    /// With only the background and the first plane's colours, the other two
+    /// are shades between them.
+    pub fn from_config(value: &Value, errors: &mut Vec<String>) -> Palette {
+        let mono = Palette::from_preset("mono").unwrap();
+        let colors = match value {
+            Value::String(name) => {
+                return Palette::from_preset(name).unwrap_or_else(|| {
+                    errors.push(format!(
+                        "palette must be one of {}, or a list of colours",
+                        PALETTE_NAMES.join(", ")
+                    ));
+                    mono
+                });
+            }
+            Value::Array(colors) if colors.len() == 2 || colors.len() == 4 => colors,
+            _ => {
+                errors.push("palette must be a name or a list of 2 or 4 colours".to_string());
+                return mono;
+            }
+        };
+
+        let mut parsed = Vec::new();
+        for color in colors {
+            match color.as_str().and_then(parse_color) {
+                Some(color) => parsed.push(color),
+                None => errors.push(format!(
+                    "palette: Invalid colour {}, expected #rrggbb",
+                    color
+                )),
+            }
+        }
+        match parsed[..] {
+            [background, foreground] => Palette([
+                background,
+                foreground,
+                mix(background, foreground, 2.0 / 3.0),
+                mix(background, foreground, 1.0 / 3.0),
+            ]),
+            [background, first, second, both] => Palette([background, first, second, both]),
+            _ => mono,
+        }
+    }
+}
+
+fn rgb(color: u32) -> Color {
+    let channel = |shift: u32| ((color >> shift) & 0xff) as f32 / 255.0;
+    [channel(16), channel(8), channel(0), 1.0]
+}
+
+fn parse_color(color: &str) -> Option<Color> {
+    if !color.starts_with('#') || color.len() != 7 {
+        return None;
+    }
+    u32::from_str_radix(&color[1..], 16).ok().map(rgb)
+}
+
+/// Mixes `color` into `background` by `amount`, from 0 to 1.
+pub fn mix(background: Color, color: Color, amount: f32) -> Color {
+    let mut mixed = background;
+    for (channel, value) in mixed.iter_mut().zip(color.iter()) {
+        *channel += (value - *channel) * amount;
+    }
+    mixed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_config(value: &str) -> (Palette, Vec<String>) {
+        let value: Value = format!("palette = {}", value).parse().unwrap();
+        let mut errors = Vec::new();
+        let palette = Palette::from_config(&value["palette"], &mut errors);
+        (palette, errors)
+    }
+
+    fn mono() -> [Color; 4] {
+        Palette::from_preset("mono").unwrap().0
+    }
+
+    #[test]
+    fn parses_colours() {
+        assert_eq!(parse_color("#ff8000"), Some([1.0, 128.0 / 255.0, 0.0, 1.0]));
+        assert_eq!(parse_color("#FF8000"), Some([1.0, 128.0 / 255.0, 0.0, 1.0]));
+        assert_eq!(parse_color("ff8000"), None);
+        assert_eq!(parse_color("#ff800"), None);
+        assert_eq!(parse_color("#ff800g"), None);
+    }
+
+    #[test]
+    fn reads_a_preset_by_name() {
+        let (palette, errors) = from_config("\"amber\"");
+        assert!(errors.is_empty());
+        assert_eq!(palette.0, Palette::from_preset("amber").unwrap().0);
+    }
+
+    #[test]
+    fn reads_four_colours() {
+        let (palette, errors) = from_config("[\"#000000\", \"#ff0000\", \"#00ff00\", \"#0000ff\"]");
+        assert!(errors.is_empty());
+        assert_eq!(
+            palette.0,
+            [
+                [0.0, 0.0, 0.0, 1.0],
+                [1.0, 0.0, 0.0, 1.0],
+                [0.0, 1.0, 0.0, 1.0],
+                [0.0, 0.0, 1.0, 1.0],
+            ]
+        );
+    }
+
+    #[test]
+    fn shades_the_planes_between_two_colours() {
+        let (palette, errors) = from_config("[\"#000000\", \"#ffffff\"]");
+        assert!(errors.is_empty());
+        assert_eq!(palette.0[0], [0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(palette.0[1], [1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(palette.0[2], mix(palette.0[0], palette.0[1], 2.0 / 3.0));
+        assert_eq!(palette.0[3], mix(palette.0[0], palette.0[1], 1.0 / 3.0));
+    }
+
+    #[test]
+    fn falls_back_to_mono_on_an_invalid_colour() {
+        let (palette, errors) = from_config("[\"#000000\", \"white\"]");
+        assert_eq!(palette.0, mono());
+        assert_eq!(
+            errors,
+            ["palette: Invalid colour \"white\", expected #rrggbb"]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_mono_on_an_invalid_setting() {
+        let (palette, errors) = from_config("\"sepia\"");
+        assert_eq!(palette.0, mono());
+        assert_eq!(
+            errors,
+            ["palette must be one of mono, green, amber, or a list of colours"]
+        );
+
+        let (palette, errors) = from_config("[\"#000000\", \"#ffffff\", \"#888888\"]");
+        assert_eq!(palette.0, mono());
+        assert_eq!(
+            errors,
+            ["palette must be a name or a list of 2 or 4 colours"]
+        );
+    }
+}
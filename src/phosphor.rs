@@ -0,0 +1,150 @@
+use chip8::cpu::user_interface::{Region, Screen};
+
+/// Simulates the persistence of a CRT's phosphor to hide the flicker of
+/// sprites erased and redrawn by XOR: pixels that go dark fade out over a
+/// number of rendered frames instead of disappearing at once. Only affects
+/// what the window shows.
+pub struct Phosphor {
+    fade_frames: u32,
+    /// Whether a pixel is lit while it is lit in either of the last two
+    /// frames.
+    blend: bool,
+    width: usize,
+    height: usize,
+    previous: Vec<u8>,
+    /// The planes each pixel was last lit in.
+    planes: Vec<u8>,
+    /// How many more frames each pixel stays visible.
+    levels: Vec<u32>,
+}
+
+impl Phosphor {
+    pub fn new(fade_frames: u32, blend: bool) -> Phosphor {
+        Phosphor {
+            fade_frames,
+            blend,
+            width: 0,
+            height: 0,
+            previous: Vec::new(),
+            planes: Vec::new(),
+            levels: Vec::new(),
+        }
+    }
+
+    /// Advances by one rendered frame showing `frame`, and returns the region
+    /// in which the pixels shown changed.
+    pub fn update(&mut self, frame: &Screen) -> Option<Region> {
+        if (frame.width(), frame.height()) != (self.width, self.height) {
+            self.width = frame.width();
+            self.height = frame.height();
+            self.previous = vec![0; self.width * self.height];
+            self.planes = vec![0; self.width * self.height];
+            self.levels = vec![0; self.width * self.height];
+        }
+
+        let mut changed: Option<Region> = None;
+        for (y, row) in frame.rows().enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                let i = y * self.width + x;
+                let lit = if self.blend {
+                    *pixel | self.previous[i]
+                } else {
+                    *pixel
+                };
+                self.previous[i] = *pixel;
+
+                let (planes, level) = if lit != 0 {
+                    (lit, self.fade_frames + 1)
+                } else {
+                    (self.planes[i], self.levels[i].saturating_sub(1))
+                };
+                if (planes, level) != (self.planes[i], self.levels[i]) {
+                    self.planes[i] = planes;
+                    self.levels[i] = level;
+                    let pixel = Region {
+                        x,
+                        y,
+                        width: 1,
+                        height: 1,
+                    };
+                    changed = Some(changed.map_or(pixel, |region| region.union(pixel)));
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// Returns the planes a pixel shows, and how brightly, from 0 to 1.
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, f32) {
+        let i = y * self.width + x;
+        let intensity = self.levels[i] as f32 / (self.fade_frames + 1) as f32;
+        (self.planes[i], intensity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen_with(pixels: &[(usize, usize, u8)]) -> Screen {
+        let mut screen = Screen::new(8, 4);
+        for (x, y, value) in pixels {
+            screen.set(*x, *y, *value);
+        }
+        screen
+    }
+
+    #[test]
+    fn dark_pixels_fade_out_over_the_fade_frames() {
+        let mut phosphor = Phosphor::new(2, false);
+        phosphor.update(&screen_with(&[(1, 2, 2)]));
+        assert_eq!(phosphor.pixel(1, 2), (2, 1.0));
+
+        let dark = screen_with(&[]);
+        let fade = Region {
+            x: 1,
+            y: 2,
+            width: 1,
+            height: 1,
+        };
+        assert_eq!(phosphor.update(&dark), Some(fade));
+        assert_eq!(phosphor.pixel(1, 2), (2, 2.0 / 3.0));
+        assert_eq!(phosphor.update(&dark), Some(fade));
+        assert_eq!(phosphor.pixel(1, 2), (2, 1.0 / 3.0));
+        assert_eq!(phosphor.update(&dark), Some(fade));
+        assert_eq!(phosphor.pixel(1, 2).1, 0.0);
+        assert_eq!(phosphor.update(&dark), None);
+    }
+
+    #[test]
+    fn without_fading_dark_pixels_disappear_at_once() {
+        let mut phosphor = Phosphor::new(0, false);
+        phosphor.update(&screen_with(&[(1, 2, 1)]));
+        assert_eq!(phosphor.pixel(1, 2), (1, 1.0));
+
+        phosphor.update(&screen_with(&[]));
+        assert_eq!(phosphor.pixel(1, 2).1, 0.0);
+    }
+
+    #[test]
+    fn blending_keeps_pixels_lit_in_the_previous_frame() {
+        let mut phosphor = Phosphor::new(0, true);
+        phosphor.update(&screen_with(&[(0, 0, 1)]));
+        phosphor.update(&screen_with(&[(0, 0, 0), (3, 1, 2)]));
+        assert_eq!(phosphor.pixel(0, 0), (1, 1.0));
+        assert_eq!(phosphor.pixel(3, 1), (2, 1.0));
+
+        phosphor.update(&screen_with(&[]));
+        assert_eq!(phosphor.pixel(0, 0).1, 0.0);
+        assert_eq!(phosphor.pixel(3, 1), (2, 1.0));
+    }
+
+    #[test]
+    fn blending_combines_the_planes_of_both_frames() {
+        let mut phosphor = Phosphor::new(0, true);
+        phosphor.update(&screen_with(&[(0, 0, 1)]));
+        phosphor.update(&screen_with(&[(0, 0, 2)]));
+        assert_eq!(phosphor.pixel(0, 0), (3, 1.0));
+    }
+}
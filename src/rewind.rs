@@ -0,0 +1,131 @@
+use crate::cpu::Snapshot;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::collections::VecDeque;
+
+pub const DEFAULT_REWIND_SECONDS: u32 = 10;
+
+/// The longest history `--rewind` accepts, as a safeguard against a value that
+/// would take more memory than the machine has. At 60 snapshots a second this
+/// is 36,000 snapshots, or around 90 MB at the sizes given for `RewindBuffer`.
+pub const MAX_REWIND_SECONDS: u32 = 600;
+
+/// A ring buffer of the most recent snapshots, one per frame, kept compressed
+/// so that several seconds of XO-CHIP's 64 KiB memory fit comfortably.
+///
+/// A snapshot takes about 6 KB uncompressed on CHIP-8 and 68 KB on XO-CHIP,
+/// almost all of it memory. Since most of that memory is unused, each one
+/// compresses to between 0.5 and 2.5 KB, so the default 10 seconds take a
+/// couple of megabytes at most.
+pub struct RewindBuffer {
+    capacity: usize,
+    states: VecDeque<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    /// Creates a buffer that holds the last `capacity` snapshots. A capacity
+    /// of 0 disables rewinding. The buffer grows as snapshots are pushed.
+    pub fn new(capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            capacity,
+            states: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// Records a snapshot, dropping the oldest one when the buffer is full.
+    pub fn push(&mut self, snapshot: &Snapshot) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.states.len() == self.capacity {
+            self.states.pop_front();
+        }
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        // Writing to a Vec can't fail.
+        snapshot.write_to(&mut encoder).unwrap();
+        self.states.push_back(encoder.finish().unwrap());
+    }
+
+    /// Removes and returns the most recent snapshot.
+    pub fn pop(&mut self) -> Option<Snapshot> {
+        let state = self.states.pop_back()?;
+        Snapshot::read_from(&mut DeflateDecoder::new(&state[..])).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::audio::NullSink;
+    use crate::cpu::user_interface::HeadlessUI;
+    use crate::cpu::{Cpu, Platform, Quirks, TimerMode};
+    use crate::cpu::random::SeededRandom;
+
+    /// A program that counts up in V0.
+    fn new_cpu() -> Cpu<HeadlessUI> {
+        let rom = vec![
+            0x70, 0x01, // ADD V0, 1
+            0x12, 0x00, // JMP 0x200
+        ];
+        Cpu::new(
+            rom,
+            HeadlessUI::new(),
+            Platform::Chip8,
+            Quirks::VIP,
+            TimerMode::Ticked,
+            Box::new(NullSink),
+            Box::new(SeededRandom::new(0)),
+        )
+        .unwrap()
+    }
+
+    /// Pushes a snapshot for each of the first `count` values of the counter.
+    fn push_counts(rewind: &mut RewindBuffer, cpu: &mut Cpu<HeadlessUI>, count: usize) {
+        for _ in 0..count {
+            cpu.execute().unwrap();
+            cpu.execute().unwrap();
+            rewind.push(&cpu.snapshot());
+        }
+    }
+
+    /// Restores the most recent snapshot and returns its counter.
+    fn pop_count(rewind: &mut RewindBuffer, cpu: &mut Cpu<HeadlessUI>) -> Option<u8> {
+        let snapshot = rewind.pop()?;
+        cpu.restore(&snapshot).unwrap();
+        Some(cpu.registers().gpr[0])
+    }
+
+    #[test]
+    fn pops_the_most_recent_snapshots_first() {
+        let mut cpu = new_cpu();
+        let mut rewind = RewindBuffer::new(3);
+        push_counts(&mut rewind, &mut cpu, 5);
+
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(pop_count(&mut rewind, &mut cpu), Some(5));
+        assert_eq!(pop_count(&mut rewind, &mut cpu), Some(4));
+        assert_eq!(pop_count(&mut rewind, &mut cpu), Some(3));
+        assert_eq!(pop_count(&mut rewind, &mut cpu), None);
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut cpu = new_cpu();
+        let mut rewind = RewindBuffer::new(0);
+        push_counts(&mut rewind, &mut cpu, 1);
+
+        assert!(rewind.is_empty());
+        assert_eq!(pop_count(&mut rewind, &mut cpu), None);
+    }
+}
@@ -0,0 +1,234 @@
+use crate::cpu::quirks::Quirks;
+use crate::cpu::{hash_rom, Platform, RomHash, Stack};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use toml::value::{Table, Value};
+
+/// Metadata about known ROMs, keyed by the hex SHA-1 of the ROM. Each entry
+/// has a `title`, and optionally an `author`, the `platform` and `quirks` it
+/// expects by the names the command line uses, the `speed` it plays best at
+/// in instructions per second, the `stack` it needs as `"memory"` or the depth
+/// of a dedicated stack, and `keys` suggesting physical keys for the CHIP-8
+/// keys it uses, named as in the config file.
+const DATABASE: &str = include_str!("roms.toml");
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    Empty,
+    /// The ROM doesn't fit in the platform's memory.
+    TooLarge {
+        size: usize,
+        max_size: usize,
+    },
+}
+
+impl From<io::Error> for RomError {
+    fn from(error: io::Error) -> RomError {
+        RomError::Io(error)
+    }
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::Io(error) => write!(f, "{}", error),
+            RomError::Empty => write!(f, "The ROM is empty"),
+            RomError::TooLarge { size, max_size } => write!(
+                f,
+                "The ROM is {} bytes, but at most {} bytes fit in memory",
+                size, max_size
+            ),
+        }
+    }
+}
+
+pub struct Rom {
+    pub bytes: Vec<u8>,
+    pub hash: RomHash,
+}
+
+impl Rom {
+    pub fn load(path: &Path) -> Result<Rom, RomError> {
+        let bytes = std::fs::read(path)?;
+        if bytes.is_empty() {
+            return Err(RomError::Empty);
+        }
+        let hash = hash_rom(&bytes);
+        Ok(Rom { bytes, hash })
+    }
+
+    /// Checks that the ROM fits in the memory of `platform`.
+    pub fn check_fits(&self, platform: Platform) -> Result<(), RomError> {
+        let max_size = platform.max_rom_size();
+        if self.bytes.len() > max_size {
+            return Err(RomError::TooLarge {
+                size: self.bytes.len(),
+                max_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Looks the ROM up in the metadata database.
+    pub fn info(&self) -> Option<RomInfo> {
+        let hash: String = self
+            .hash
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let database: Value = DATABASE.parse().expect("The ROM database is invalid");
+        match database.get(&hash)? {
+            Value::Table(entry) => Some(RomInfo::from_entry(entry)),
+            _ => None,
+        }
+    }
+}
+
+/// What the metadata database knows about a ROM. Settings it leaves out are
+/// `None`.
+pub struct RomInfo {
+    pub title: String,
+    pub author: Option<String>,
+    pub platform: Option<Platform>,
+    pub quirks: Option<Quirks>,
+    pub clock_rate: Option<u32>,
+    pub stack: Option<Stack>,
+    /// The CHIP-8 keys the ROM uses, each with the name of a physical key
+    /// suited to it.
+    pub keys: Vec<(usize, String)>,
+}
+
+impl RomInfo {
+    fn from_entry(entry: &Table) -> RomInfo {
+        let string = |field: &str| entry.get(field).and_then(Value::as_str);
+        let keys = match entry.get("keys") {
+            Some(Value::Table(keys)) => keys
+                .iter()
+                .filter_map(|(chip8_key, name)| {
+                    let key_code = usize::from_str_radix(chip8_key, 16)
+                        .ok()
+                        .filter(|key_code| *key_code < 16)?;
+                    Some((key_code, name.as_str()?.to_string()))
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        RomInfo {
+            title: string("title").unwrap_or_default().to_string(),
+            author: string("author").map(str::to_string),
+            platform: string("platform").and_then(Platform::from_name),
+            quirks: string("quirks").and_then(Quirks::from_preset),
+            clock_rate: entry
+                .get("speed")
+                .and_then(Value::as_integer)
+                .filter(|speed| *speed > 0 && *speed <= i64::from(u32::MAX))
+                .map(|speed| speed as u32),
+            stack: match entry.get("stack") {
+                Some(Value::String(stack)) if stack == "memory" => Some(Stack::Memory),
+                Some(Value::Integer(depth)) if *depth > 0 && *depth <= i64::from(u16::MAX) => {
+                    Some(Stack::Dedicated(*depth as usize))
+                }
+                _ => None,
+            },
+            keys,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(bytes: &[u8]) -> Rom {
+        Rom {
+            bytes: bytes.to_vec(),
+            hash: hash_rom(bytes),
+        }
+    }
+
+    #[test]
+    fn looks_up_a_known_rom_by_its_hash() {
+        let info = rom(include_bytes!("../roms/INVADERS")).info().unwrap();
+        assert_eq!(info.title, "Space Invaders");
+        assert_eq!(info.author.as_deref(), Some("David Winter"));
+        assert!(info.platform == Some(Platform::Chip8));
+        assert!(info.quirks == Some(Quirks::CHIP48));
+        assert_eq!(info.clock_rate, Some(700));
+        assert!(info.stack.is_none());
+        assert_eq!(
+            info.keys,
+            [
+                (4, "Left".to_string()),
+                (5, "Space".to_string()),
+                (6, "Right".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_the_stack_an_entry_needs() {
+        let stack = |entry: &str| {
+            let entry: Value = format!("title = \"Test\"\n{}", entry).parse().unwrap();
+            RomInfo::from_entry(entry.as_table().unwrap()).stack
+        };
+        assert!(stack("stack = \"memory\"") == Some(Stack::Memory));
+        assert!(stack("stack = 12") == Some(Stack::Dedicated(12)));
+        assert!(stack("stack = 0").is_none());
+        assert!(stack("stack = \"dedicated\"").is_none());
+        assert!(stack("").is_none());
+    }
+
+    #[test]
+    fn unknown_rom_has_no_info() {
+        assert!(rom(&[0x12, 0x00]).info().is_none());
+    }
+
+    #[test]
+    fn every_database_entry_is_valid() {
+        let database: Value = DATABASE.parse().unwrap();
+        for (hash, entry) in database.as_table().unwrap() {
+            let entry = entry.as_table().unwrap();
+            let info = RomInfo::from_entry(entry);
+            assert_eq!(hash.len(), 40, "{}", hash);
+            assert!(!info.title.is_empty(), "{}", hash);
+            let has = |field| entry.contains_key(field);
+            assert!(info.platform.is_some() == has("platform"), "{}", hash);
+            assert!(info.quirks.is_some() == has("quirks"), "{}", hash);
+            assert!(info.clock_rate.is_some() == has("speed"), "{}", hash);
+            assert!(info.stack.is_some() == has("stack"), "{}", hash);
+        }
+    }
+
+    #[test]
+    fn rejects_a_rom_too_large_for_the_platform() {
+        let max_size = Platform::Chip8.max_rom_size();
+        assert_eq!(max_size, 0x1000 - 0x200);
+        assert!(rom(&vec![0; max_size]).check_fits(Platform::Chip8).is_ok());
+
+        let too_large = rom(&vec![0; max_size + 1]);
+        match too_large.check_fits(Platform::Chip8) {
+            Err(RomError::TooLarge {
+                size: 0xe01,
+                max_size: 0xe00,
+            }) => {}
+            _ => panic!("accepted a ROM too large for CHIP-8"),
+        }
+        assert!(too_large.check_fits(Platform::XoChip).is_ok());
+    }
+
+    #[test]
+    fn rejects_an_empty_rom() {
+        let path = std::env::temp_dir().join(format!("chip8-test-{}.ch8", std::process::id()));
+        std::fs::write(&path, []).unwrap();
+        let result = Rom::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(RomError::Empty) => {}
+            _ => panic!("loaded an empty ROM"),
+        }
+    }
+}
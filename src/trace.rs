@@ -0,0 +1,491 @@
+use crate::cpu::opcode::Opcode;
+use crate::cpu::{Platform, Registers};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+
+const MAGIC: &[u8; 4] = b"C8TR";
+
+/// The version of the trace format. Bump it whenever the format changes.
+const VERSION: u16 = 1;
+
+const REGISTERS_SIZE: usize = 16 + 3 * 2 + 2;
+const RECORD_SIZE: usize = 8 + 2 + 1 + 2 * REGISTERS_SIZE;
+
+#[derive(Debug)]
+pub enum TraceError {
+    Io(io::Error),
+    NotATrace,
+    UnsupportedVersion(u16),
+    /// The trace is truncated.
+    Corrupt,
+}
+
+impl From<io::Error> for TraceError {
+    fn from(error: io::Error) -> TraceError {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => TraceError::Corrupt,
+            _ => TraceError::Io(error),
+        }
+    }
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::Io(error) => write!(f, "{}", error),
+            TraceError::NotATrace => write!(f, "Not a trace"),
+            TraceError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported trace version {}", version)
+            }
+            TraceError::Corrupt => write!(f, "The trace is corrupt"),
+        }
+    }
+}
+
+/// An executed instruction, with the registers before and after it.
+pub struct TraceRecord {
+    /// The platform the program ran on, which decides what the opcode means.
+    pub platform: Platform,
+    /// The number of instructions executed before this one.
+    pub cycle: u64,
+    pub opcode: u16,
+    /// Whether the instruction stopped the program with a fault. `after` is
+    /// then the state the fault left.
+    pub fault: bool,
+    pub before: Registers,
+    pub after: Registers,
+}
+
+impl TraceRecord {
+    /// Returns whether the instruction changed anything besides the program
+    /// counter.
+    pub fn changes_state(&self) -> bool {
+        let mut after = self.after;
+        after.program_counter = self.before.program_counter;
+        after != self.before
+    }
+
+    /// Returns a rendering of the record with every register, I, the stack
+    /// pointer and the timers before and after, changed or not, so that
+    /// traces from different emulators can be compared line by line.
+    pub fn full_state(&self) -> FullState<'_> {
+        FullState(self)
+    }
+
+    fn write_instruction(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>10} {:#06x} {:04x} {:<24}",
+            self.cycle,
+            self.before.program_counter,
+            self.opcode,
+            Opcode::decode(self.opcode, self.platform).to_string()
+        )
+    }
+}
+
+/// Renders the record as a line of text: the cycle, the address, the opcode
+/// and its mnemonic, followed by the registers it changed, with their values
+/// before and after.
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (before, after) = (&self.before, &self.after);
+        self.write_instruction(f)?;
+        for (reg, (old, new)) in before.gpr.iter().zip(after.gpr.iter()).enumerate() {
+            if old != new {
+                write!(f, " V{:X}={:02x}->{:02x}", reg, old, new)?;
+            }
+        }
+        if before.index != after.index {
+            write!(f, " I={:#06x}->{:#06x}", before.index, after.index)?;
+        }
+        if before.stack_pointer != after.stack_pointer {
+            write!(
+                f,
+                " SP={:#06x}->{:#06x}",
+                before.stack_pointer, after.stack_pointer
+            )?;
+        }
+        if before.delay_timer != after.delay_timer {
+            write!(f, " DT={}->{}", before.delay_timer, after.delay_timer)?;
+        }
+        if before.sound_timer != after.sound_timer {
+            write!(f, " ST={}->{}", before.sound_timer, after.sound_timer)?;
+        }
+        if self.fault {
+            write!(f, " FAULT")?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders a record as a line of text with the complete state before and
+/// after the instruction. See `TraceRecord::full_state`.
+pub struct FullState<'a>(&'a TraceRecord);
+
+impl fmt::Display for FullState<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.write_instruction(f)?;
+        write_state(f, &self.0.before)?;
+        write!(f, " ->")?;
+        write_state(f, &self.0.after)?;
+        if self.0.fault {
+            write!(f, " FAULT")?;
+        }
+        Ok(())
+    }
+}
+
+fn write_state(f: &mut fmt::Formatter<'_>, registers: &Registers) -> fmt::Result {
+    write!(f, " V=")?;
+    for value in registers.gpr.iter() {
+        write!(f, "{:02x}", value)?;
+    }
+    write!(
+        f,
+        " I={:#06x} SP={:#06x} DT={:02x} ST={:02x}",
+        registers.index, registers.stack_pointer, registers.delay_timer, registers.sound_timer
+    )
+}
+
+/// Which instructions a trace keeps. By default, all of them.
+#[derive(Clone, Default)]
+pub struct TraceFilter {
+    /// Only the instructions at these addresses.
+    pub addresses: Option<RangeInclusive<usize>>,
+    /// Only the instructions whose first nibble is one of these, such as 0xD
+    /// for `DRW`.
+    pub classes: Option<Vec<u8>>,
+    /// Only the instructions that change a register, I, the stack pointer or
+    /// a timer.
+    pub changes_only: bool,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, record: &TraceRecord) -> bool {
+        if let Some(addresses) = &self.addresses {
+            if !addresses.contains(&record.before.program_counter) {
+                return false;
+            }
+        }
+        if let Some(classes) = &self.classes {
+            if !classes.contains(&((record.opcode >> 12) as u8)) {
+                return false;
+            }
+        }
+        !self.changes_only || record.changes_state()
+    }
+}
+
+/// Writes a trace as the program runs, in a compact binary format: a header
+/// of the magic bytes, the format version and the platform, followed by one
+/// fixed-size record per instruction kept. Numbers are big-endian, and
+/// addresses are 16 bits, as no platform has more memory than that.
+pub struct Tracer {
+    writer: Box<dyn Write + Send>,
+    platform: Platform,
+    filter: TraceFilter,
+    cycle: u64,
+}
+
+impl Tracer {
+    pub fn new(
+        mut writer: Box<dyn Write + Send>,
+        platform: Platform,
+        filter: TraceFilter,
+    ) -> io::Result<Tracer> {
+        writer.write_all(MAGIC)?;
+        writer.write_u16::<BigEndian>(VERSION)?;
+        writer.write_u8(platform.id())?;
+        Ok(Tracer {
+            writer,
+            platform,
+            filter,
+            cycle: 0,
+        })
+    }
+
+    /// Records an executed instruction, if the filter keeps it. Every call
+    /// counts as a cycle, kept or not.
+    pub fn record(&mut self, opcode: u16, before: Registers, after: Registers) -> io::Result<()> {
+        let record = self.next_record(opcode, false, before, after);
+        if !self.filter.matches(&record) {
+            return Ok(());
+        }
+        self.write_record(&record)
+    }
+
+    /// Records an instruction that faulted. It is kept whatever the filter,
+    /// as it is usually the one being looked for.
+    pub fn record_fault(
+        &mut self,
+        opcode: u16,
+        before: Registers,
+        after: Registers,
+    ) -> io::Result<()> {
+        let record = self.next_record(opcode, true, before, after);
+        self.write_record(&record)
+    }
+
+    /// Counts an instruction that executed without recording it, such as
+    /// `MOV Vx, K` waiting for a key, so that the cycles of the records that
+    /// follow stay true.
+    pub fn skip(&mut self) {
+        self.cycle += 1;
+    }
+
+    fn next_record(
+        &mut self,
+        opcode: u16,
+        fault: bool,
+        before: Registers,
+        after: Registers,
+    ) -> TraceRecord {
+        let record = TraceRecord {
+            platform: self.platform,
+            cycle: self.cycle,
+            opcode,
+            fault,
+            before,
+            after,
+        };
+        self.cycle += 1;
+        record
+    }
+
+    fn write_record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        self.writer.write_u64::<BigEndian>(record.cycle)?;
+        self.writer.write_u16::<BigEndian>(record.opcode)?;
+        self.writer.write_u8(record.fault as u8)?;
+        write_registers(&mut self.writer, &record.before)?;
+        write_registers(&mut self.writer, &record.after)
+    }
+
+    /// Writes out buffered records, so that the trace survives the program
+    /// being closed.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads the records of a trace one at a time, as traces can be long.
+pub struct TraceReader<'a> {
+    reader: &'a mut dyn Read,
+    platform: Platform,
+}
+
+impl<'a> TraceReader<'a> {
+    pub fn new(reader: &'a mut dyn Read) -> Result<TraceReader<'a>, TraceError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(TraceError::NotATrace);
+        }
+        let version = reader.read_u16::<BigEndian>()?;
+        if version != VERSION {
+            return Err(TraceError::UnsupportedVersion(version));
+        }
+        let platform = Platform::from_id(reader.read_u8()?).ok_or(TraceError::Corrupt)?;
+
+        Ok(TraceReader { reader, platform })
+    }
+
+    /// Returns the next record, or `None` at the end of the trace.
+    pub fn next_record(&mut self) -> Result<Option<TraceRecord>, TraceError> {
+        let mut record = [0; RECORD_SIZE];
+        let mut filled = 0;
+        while filled < RECORD_SIZE {
+            match self.reader.read(&mut record[filled..])? {
+                0 => break,
+                count => filled += count,
+            }
+        }
+        match filled {
+            0 => return Ok(None),
+            RECORD_SIZE => {}
+            _ => return Err(TraceError::Corrupt),
+        }
+
+        let mut record = &record[..];
+        Ok(Some(TraceRecord {
+            platform: self.platform,
+            cycle: record.read_u64::<BigEndian>()?,
+            opcode: record.read_u16::<BigEndian>()?,
+            fault: record.read_u8()? != 0,
+            before: read_registers(&mut record)?,
+            after: read_registers(&mut record)?,
+        }))
+    }
+}
+
+fn write_registers(writer: &mut dyn Write, registers: &Registers) -> io::Result<()> {
+    writer.write_all(&registers.gpr)?;
+    writer.write_u16::<BigEndian>(registers.program_counter as u16)?;
+    writer.write_u16::<BigEndian>(registers.index as u16)?;
+    writer.write_u16::<BigEndian>(registers.stack_pointer as u16)?;
+    writer.write_u8(registers.delay_timer)?;
+    writer.write_u8(registers.sound_timer)
+}
+
+fn read_registers(reader: &mut dyn Read) -> io::Result<Registers> {
+    let mut gpr = [0; 16];
+    reader.read_exact(&mut gpr)?;
+    Ok(Registers {
+        gpr,
+        program_counter: reader.read_u16::<BigEndian>()?.into(),
+        index: reader.read_u16::<BigEndian>()?.into(),
+        stack_pointer: reader.read_u16::<BigEndian>()?.into(),
+        delay_timer: reader.read_u8()?,
+        sound_timer: reader.read_u8()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A writer whose bytes can still be read once the tracer owns it.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn registers(program_counter: usize, v0: u8) -> Registers {
+        let mut gpr = [0; 16];
+        gpr[0] = v0;
+        Registers {
+            gpr,
+            program_counter,
+            index: 0xfff0,
+            stack_pointer: 0xefe,
+            delay_timer: 3,
+            sound_timer: 0,
+        }
+    }
+
+    /// Traces `MOV V0, 1` at 0x200, `JMP 0x206` at 0x202, a wait for a key at
+    /// 0x206, `ADD V0, 1` at 0x206 and a faulting word at 0x208.
+    fn trace(filter: TraceFilter) -> Vec<TraceRecord> {
+        let buffer = SharedBuffer::default();
+        let mut tracer = Tracer::new(Box::new(buffer.clone()), Platform::XoChip, filter).unwrap();
+        tracer
+            .record(0x6001, registers(0x200, 0), registers(0x202, 1))
+            .unwrap();
+        tracer
+            .record(0x1206, registers(0x202, 1), registers(0x206, 1))
+            .unwrap();
+        tracer.skip();
+        tracer
+            .record(0x7001, registers(0x206, 1), registers(0x208, 2))
+            .unwrap();
+        tracer
+            .record_fault(0xffff, registers(0x208, 2), registers(0x208, 2))
+            .unwrap();
+        tracer.flush().unwrap();
+
+        let bytes = buffer.0.lock().unwrap().clone();
+        let mut file = &bytes[..];
+        let mut reader = TraceReader::new(&mut file).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            records.push(record);
+        }
+        records
+    }
+
+    fn cycles(records: &[TraceRecord]) -> Vec<u64> {
+        records.iter().map(|record| record.cycle).collect()
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let records = trace(TraceFilter::default());
+
+        assert_eq!(cycles(&records), [0, 1, 3, 4]);
+        let record = &records[2];
+        assert!(record.platform == Platform::XoChip);
+        assert_eq!(record.opcode, 0x7001);
+        assert!(!record.fault);
+        assert!(record.before == registers(0x206, 1));
+        assert!(record.after == registers(0x208, 2));
+        assert!(records[3].fault);
+    }
+
+    #[test]
+    fn keeps_the_addresses_in_range() {
+        let records = trace(TraceFilter {
+            addresses: Some(0x202..=0x206),
+            ..TraceFilter::default()
+        });
+
+        assert_eq!(cycles(&records), [1, 3, 4]);
+    }
+
+    #[test]
+    fn keeps_the_instruction_classes_asked_for() {
+        let records = trace(TraceFilter {
+            classes: Some(vec![0x6, 0x7]),
+            ..TraceFilter::default()
+        });
+
+        assert_eq!(cycles(&records), [0, 3, 4]);
+    }
+
+    #[test]
+    fn keeps_only_the_instructions_that_change_state() {
+        let records = trace(TraceFilter {
+            changes_only: true,
+            ..TraceFilter::default()
+        });
+
+        assert_eq!(cycles(&records), [0, 3, 4]);
+    }
+
+    #[test]
+    fn renders_the_changes() {
+        let records = trace(TraceFilter::default());
+
+        assert_eq!(
+            records[0].to_string(),
+            "         0 0x0200 6001 MOV V0, 0x01             V0=00->01"
+        );
+        assert_eq!(
+            records[3].to_string(),
+            "         4 0x0208 ffff dw 0xffff                FAULT"
+        );
+    }
+
+    #[test]
+    fn rejects_a_truncated_record() {
+        let buffer = SharedBuffer::default();
+        let mut tracer = Tracer::new(
+            Box::new(buffer.clone()),
+            Platform::Chip8,
+            TraceFilter::default(),
+        )
+        .unwrap();
+        tracer
+            .record(0x6001, registers(0x200, 0), registers(0x202, 1))
+            .unwrap();
+        let mut bytes = buffer.0.lock().unwrap().clone();
+        bytes.pop();
+
+        let mut file = &bytes[..];
+        let mut reader = TraceReader::new(&mut file).unwrap();
+        match reader.next_record() {
+            Err(TraceError::Corrupt) => {}
+            _ => panic!("read half a record"),
+        }
+    }
+}